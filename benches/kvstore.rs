@@ -1,3 +1,5 @@
+use std::thread;
+
 use criterion::{criterion_group, criterion_main, Criterion};
 use kvs::engine::*;
 use rand::Rng;
@@ -12,32 +14,32 @@ fn format_key_value(c: &mut Criterion) {
 	let sled = SledKvsEngine::open(sled_dir.path()).unwrap();
 	c.bench_function("kvs write", |b| b.iter(|| {
 		for i in 0..10000 {
-			assert!(kvs.set(format!("key{}", i), format!("value{}", i)).is_ok());
+			assert!(kvs.set(format!("key{}", i).into_bytes(), format!("value{}", i).into_bytes()).is_ok());
 		}
 	}));
 
 	c.bench_function("kvs read", |b| b.iter(|| {
 		for i in 0..10000 {
-			assert!(kvs.get(format!("key{}", i)).is_ok());
+			assert!(kvs.get(format!("key{}", i).into_bytes()).is_ok());
 		}
 	}));
 
 	c.bench_function("sled write", |b| b.iter(|| {
 		for i in 0..100 {
-			assert!(sled.set(format!("key{}", i), format!("value{}", i)).is_ok());
+			assert!(sled.set(format!("key{}", i).into_bytes(), format!("value{}", i).into_bytes()).is_ok());
 		}
 	}));
 
 	c.bench_function("sled read", |b| b.iter(|| {
 		for i in 0..100 {
-			assert!(sled.get(format!("key{}", i)).is_ok());
+			assert!(sled.get(format!("key{}", i).into_bytes()).is_ok());
 		}
 	}));
 }
 
 fn random_generated_key_value(c: &mut Criterion) {
-	let mut keys:Vec<String> = vec![];
-	let mut values: Vec<String> = vec![];
+	let mut keys:Vec<Vec<u8>> = vec![];
+	let mut values: Vec<Vec<u8>> = vec![];
 	println!("Init keys and values...");
 	for _ in 0..100 {
 		let klen = thread_rng().gen_range(1, 100000);
@@ -46,14 +48,16 @@ fn random_generated_key_value(c: &mut Criterion) {
 			thread_rng()
 			.sample_iter(&Alphanumeric)
 			.take(klen)
-			.collect()
+			.collect::<String>()
+			.into_bytes()
 		);
 
 		values.push(
 			thread_rng()
 			.sample_iter(&Alphanumeric)
 			.take(vlen)
-			.collect()
+			.collect::<String>()
+			.into_bytes()
 		);
 	}
 
@@ -90,5 +94,59 @@ fn random_generated_key_value(c: &mut Criterion) {
 	}));
 }
 
-criterion_group!(benches, format_key_value, random_generated_key_value);
+fn concurrent_write(c: &mut Criterion) {
+	const THREADS: usize = 8;
+	const WRITES: usize = 1000;
+
+	let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+	let options = KvStoreOptions { sync: SyncPolicy::EveryWrite, ..KvStoreOptions::default() };
+	let kvs = KvStore::open_with_options(kvs_dir.path(), options).unwrap();
+
+	// the same number of synced writes, from one thread and from several:
+	// concurrent writers share flushes and syncs through group commit
+	c.bench_function("kvs synced write, 1 thread", |b| b.iter(|| {
+		for i in 0..THREADS * WRITES {
+			assert!(kvs.set(format!("key{}", i).into_bytes(), format!("value{}", i).into_bytes()).is_ok());
+		}
+	}));
+
+	c.bench_function("kvs synced write, 8 threads", |b| b.iter(|| {
+		let handles: Vec<_> = (0..THREADS).map(|t| {
+			let kvs = kvs.clone();
+			thread::spawn(move || {
+				for i in 0..WRITES {
+					let key = format!("key{}", t * WRITES + i).into_bytes();
+					assert!(kvs.set(key, format!("value{}", i).into_bytes()).is_ok());
+				}
+			})
+		}).collect();
+		for handle in handles {
+			handle.join().unwrap();
+		}
+	}));
+}
+
+fn log_codec(c: &mut Criterion) {
+	let value: Vec<u8> = (0..100000).map(|i| i as u8).collect();
+
+	for (name, codec) in &[("json", LogCodec::Json), ("bincode", LogCodec::Bincode)] {
+		let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+		let options = KvStoreOptions { codec: *codec, ..KvStoreOptions::default() };
+		let kvs = KvStore::open_with_options(kvs_dir.path(), options).unwrap();
+
+		c.bench_function(&format!("kvs {} write", name), |b| b.iter(|| {
+			for i in 0..100 {
+				assert!(kvs.set(format!("key{}", i).into_bytes(), value.clone()).is_ok());
+			}
+		}));
+
+		c.bench_function(&format!("kvs {} read", name), |b| b.iter(|| {
+			for i in 0..100 {
+				assert!(kvs.get(format!("key{}", i).into_bytes()).is_ok());
+			}
+		}));
+	}
+}
+
+criterion_group!(benches, format_key_value, random_generated_key_value, concurrent_write, log_codec);
 criterion_main!(benches);
\ No newline at end of file
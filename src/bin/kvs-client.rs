@@ -1,8 +1,10 @@
 #[macro_use]
 extern crate clap;
 
+use std::io::{self, Write};
+use std::ops::Bound;
 use std::process;
-use std::str::FromStr;
+use std::time::Duration;
 
 use clap::AppSettings;
 use clap::{App, Arg, SubCommand};
@@ -11,7 +13,14 @@ use kvs::err::*;
 use log::LevelFilter;
 
 #[tokio::main]
-async fn main() -> Result<()> {
+async fn main() {
+    if let Err(e) = run().await {
+        eprintln!("{}", e);
+        process::exit(-1);
+    }
+}
+
+async fn run() -> Result<()> {
     env_logger::builder()
         .filter_level(LevelFilter::Info)
         .init();
@@ -39,6 +48,10 @@ async fn main() -> Result<()> {
                 .index(2)
                 .required(true)
             )
+            .arg(Arg::with_name("ttl")
+                               .long("ttl")
+                               .value_name("seconds")
+                               .help("Makes the key expire after this many seconds"))
             .arg(Arg::with_name("server address")
                                .short("s")
                                .long("addr")
@@ -78,6 +91,139 @@ async fn main() -> Result<()> {
                                .help("Sets a server to connect")
                                .default_value("127.0.0.1:4000"))
         )
+        .subcommand(
+            SubCommand::with_name("cas")
+            .about("Set a key-value, or remove it, only if it holds the expected value")
+            .arg(
+                Arg::with_name("key")
+                .help("key")
+                .index(1)
+                .required(true)
+            )
+            .arg(
+                Arg::with_name("value")
+                .help("new value, the key is removed without one")
+                .index(2)
+            )
+            .arg(Arg::with_name("expected")
+                               .short("x")
+                               .long("expect")
+                               .value_name("expected")
+                               .help("Value the key must hold, it must be missing without one"))
+            .arg(Arg::with_name("server address")
+                               .short("s")
+                               .long("addr")
+                               .value_name("server_address")
+                               .help("Sets a server to connect")
+                               .default_value("127.0.0.1:4000"))
+        )
+        .subcommand(
+            SubCommand::with_name("append")
+            .about("Append to a value, a missing key counting as empty")
+            .arg(
+                Arg::with_name("key")
+                .help("key")
+                .index(1)
+                .required(true)
+            )
+            .arg(
+                Arg::with_name("suffix")
+                .help("suffix")
+                .index(2)
+                .required(true)
+            )
+            .arg(Arg::with_name("server address")
+                               .short("s")
+                               .long("addr")
+                               .value_name("server_address")
+                               .help("Sets a server to connect")
+                               .default_value("127.0.0.1:4000"))
+        )
+        .subcommand(
+            SubCommand::with_name("incr")
+            .about("Add to an integer value, a missing key counting as 0")
+            .arg(
+                Arg::with_name("key")
+                .help("key")
+                .index(1)
+                .required(true)
+            )
+            .arg(
+                Arg::with_name("delta")
+                .help("amount to add")
+                .index(2)
+                .default_value("1")
+            )
+            .arg(Arg::with_name("server address")
+                               .short("s")
+                               .long("addr")
+                               .value_name("server_address")
+                               .help("Sets a server to connect")
+                               .default_value("127.0.0.1:4000"))
+        )
+        .subcommand(
+            SubCommand::with_name("decr")
+            .about("Subtract from an integer value, a missing key counting as 0")
+            .arg(
+                Arg::with_name("key")
+                .help("key")
+                .index(1)
+                .required(true)
+            )
+            .arg(
+                Arg::with_name("delta")
+                .help("amount to subtract")
+                .index(2)
+                .default_value("1")
+            )
+            .arg(Arg::with_name("server address")
+                               .short("s")
+                               .long("addr")
+                               .value_name("server_address")
+                               .help("Sets a server to connect")
+                               .default_value("127.0.0.1:4000"))
+        )
+        .subcommand(
+            SubCommand::with_name("scan")
+            .about("List key-values in key order")
+            .arg(
+                Arg::with_name("start")
+                .help("first key of the range, inclusive")
+                .index(1)
+            )
+            .arg(
+                Arg::with_name("end")
+                .help("end key of the range, exclusive")
+                .index(2)
+            )
+            .arg(Arg::with_name("prefix")
+                               .short("p")
+                               .long("prefix")
+                               .value_name("prefix")
+                               .help("Only list keys starting with prefix")
+                               .conflicts_with_all(&["start", "end"]))
+            .arg(Arg::with_name("limit")
+                               .short("n")
+                               .long("limit")
+                               .value_name("limit")
+                               .help("Lists at most limit key-values"))
+            .arg(Arg::with_name("server address")
+                               .short("s")
+                               .long("addr")
+                               .value_name("server_address")
+                               .help("Sets a server to connect")
+                               .default_value("127.0.0.1:4000"))
+        )
+        .subcommand(
+            SubCommand::with_name("compact")
+            .about("Reclaim the space of overwritten and removed key-values")
+            .arg(Arg::with_name("server address")
+                               .short("s")
+                               .long("addr")
+                               .value_name("server_address")
+                               .help("Sets a server to connect")
+                               .default_value("127.0.0.1:4000"))
+        )
         .get_matches();
 
     match matches.subcommand() {
@@ -85,17 +231,26 @@ async fn main() -> Result<()> {
             let address = _matches.value_of("server address")
                 .unwrap()
                 .parse()?;
-            let k = String::from_str(_matches.values_of("key").unwrap().last().unwrap()).unwrap();
-            let v = String::from_str(_matches.values_of("value").unwrap().last().unwrap()).unwrap();
+            let k = _matches.values_of("key").unwrap().last().unwrap().as_bytes().to_vec();
+            let v = _matches.values_of("value").unwrap().last().unwrap().as_bytes().to_vec();
+
+            let ttl = if _matches.is_present("ttl") {
+                Some(value_t!(_matches, "ttl", u64).unwrap_or_else(|e| e.exit()))
+            } else {
+                None
+            };
 
             let mut kv = KvsClient::new(address).await?;
-            kv.set(k, v).await?;
+            match ttl {
+                Some(secs) => kv.set_with_ttl(k, v, Duration::from_secs(secs)).await?,
+                None => kv.set(k, v).await?,
+            }
         },
         ("rm", Some(_matches)) => {
             let address = _matches.value_of("server address")
                 .unwrap()
                 .parse()?;
-            let k = String::from_str(_matches.values_of("key").unwrap().last().unwrap()).unwrap();
+            let k = _matches.values_of("key").unwrap().last().unwrap().as_bytes().to_vec();
 
             let mut kv = KvsClient::new(address).await?;
 
@@ -114,15 +269,101 @@ async fn main() -> Result<()> {
             let address = _matches.value_of("server address")
                 .unwrap()
                 .parse()?;
-            let k = String::from_str(_matches.values_of("key").unwrap().last().unwrap()).unwrap();
+            let k = _matches.values_of("key").unwrap().last().unwrap().as_bytes().to_vec();
             let mut kv = KvsClient::new(address).await?;
 
             let v = kv.get(k).await?;
             match v {
-                Some(v) => println!("{}", v),
+                Some(v) => {
+                    let mut out = io::stdout();
+                    out.write_all(&v)?;
+                    out.write_all(b"\n")?;
+                },
                 None => println!("Key not found")
             }
         },
+        ("cas", Some(_matches)) => {
+            let address = _matches.value_of("server address")
+                .unwrap()
+                .parse()?;
+            let k = _matches.value_of("key").unwrap().as_bytes().to_vec();
+            let expected = _matches.value_of("expected").map(|v| v.as_bytes().to_vec());
+            let v = _matches.value_of("value").map(|v| v.as_bytes().to_vec());
+
+            let mut kv = KvsClient::new(address).await?;
+            if let Err(e) = kv.compare_and_swap(k, expected, v).await {
+                eprintln!("{}", e);
+                process::exit(-1);
+            }
+        },
+        ("append", Some(_matches)) => {
+            let address = _matches.value_of("server address")
+                .unwrap()
+                .parse()?;
+            let k = _matches.value_of("key").unwrap().as_bytes().to_vec();
+            let suffix = _matches.value_of("suffix").unwrap().as_bytes().to_vec();
+
+            let mut kv = KvsClient::new(address).await?;
+            println!("{}", kv.append(k, suffix).await?);
+        },
+        (cmd @ "incr", Some(_matches)) | (cmd @ "decr", Some(_matches)) => {
+            let address = _matches.value_of("server address")
+                .unwrap()
+                .parse()?;
+            let k = _matches.value_of("key").unwrap().as_bytes().to_vec();
+            let delta = value_t!(_matches, "delta", i64).unwrap_or_else(|e| e.exit());
+
+            let mut kv = KvsClient::new(address).await?;
+            let res = if cmd == "incr" {
+                kv.incr(k, delta).await
+            } else {
+                kv.decr(k, delta).await
+            };
+            match res {
+                Ok(n) => println!("{}", n),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    process::exit(-1);
+                },
+            }
+        },
+        ("scan", Some(_matches)) => {
+            let address = _matches.value_of("server address")
+                .unwrap()
+                .parse()?;
+            let limit = if _matches.is_present("limit") {
+                Some(value_t!(_matches, "limit", usize).unwrap_or_else(|e| e.exit()))
+            } else {
+                None
+            };
+            let mut kv = KvsClient::new(address).await?;
+
+            let pairs = match _matches.value_of("prefix") {
+                Some(p) => kv.prefix(p.as_bytes().to_vec(), limit).await?,
+                None => {
+                    let bound = |name, end| match _matches.value_of(name) {
+                        Some(k) if end => Bound::Excluded(k.as_bytes().to_vec()),
+                        Some(k) => Bound::Included(k.as_bytes().to_vec()),
+                        None => Bound::Unbounded,
+                    };
+                    kv.scan(bound("start", false), bound("end", true), limit).await?
+                },
+            };
+            let mut out = io::stdout();
+            for (k, v) in pairs {
+                out.write_all(&k)?;
+                out.write_all(b" ")?;
+                out.write_all(&v)?;
+                out.write_all(b"\n")?;
+            }
+        },
+        ("compact", Some(_matches)) => {
+            let address = _matches.value_of("server address")
+                .unwrap()
+                .parse()?;
+            let mut kv = KvsClient::new(address).await?;
+            kv.compact().await?;
+        },
         _ => Err(KvsError::SubCmdError)?,
     }
     Ok(())
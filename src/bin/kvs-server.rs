@@ -1,30 +1,19 @@
 #[macro_use]
 extern crate clap;
 
-use std::env::current_dir;
-use std::{path::Path, fs};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
 
 use clap::{App, Arg, AppSettings};
-use kvs::engine::SledKvsEngine;
-use kvs::server::KvsServer;
+use kvs::engine::{KvStoreOptions, SledKvsEngine};
+use kvs::server::{AsyncKvsServer, KvsServer};
 use kvs::thread_pool::{NaiveThreadPool, ThreadPool};
 use kvs::{KvStore, KvsEngine};
 use log::*;
 
 use kvs::err::*;
 
-const ENGINE_FLAG_FILE: &str = ".engine_flag";
-
-fn judge_engine_flag(name: &str) -> Result<bool> {
-    if !Path::new(ENGINE_FLAG_FILE).exists() {
-        fs::write(ENGINE_FLAG_FILE, name)?;
-        Ok(true)
-    } else {
-        let exist = fs::read(ENGINE_FLAG_FILE)?;
-        Ok(exist.eq(name.as_bytes()))
-    }
-}
-
 fn main() -> Result<()> {
     env_logger::builder()
         .filter_level(LevelFilter::Info)
@@ -49,6 +38,32 @@ fn main() -> Result<()> {
                                .value_name("engine_name")
                                .help("Sets an engine type of storage")
                                .default_value("kvs"))
+        .arg(Arg::with_name("data dir")
+                               .short("d")
+                               .long("data-dir")
+                               .value_name("data_dir")
+                               .help("Sets the directory holding the store")
+                               .default_value("."))
+        .arg(Arg::with_name("sync policy")
+                               .long("sync")
+                               .value_name("sync_policy")
+                               .help("Sets when kvs writes are synced to disk: never, always, every:<writes> or interval:<millis>")
+                               .default_value("never"))
+        .arg(Arg::with_name("log codec")
+                               .long("codec")
+                               .value_name("log_codec")
+                               .help("Sets how a new kvs store encodes its logs: json or bincode")
+                               .default_value("json"))
+        .arg(Arg::with_name("upgrade")
+                               .long("upgrade")
+                               .help("Upgrades a kvs store left by an older version before serving it"))
+        .arg(Arg::with_name("read only")
+                               .long("read-only")
+                               .help("Serves a kvs store without writing to it, next to any server writing to it")
+                               .conflicts_with("upgrade"))
+        .arg(Arg::with_name("async")
+                               .long("async")
+                               .help("Serves connections on tokio instead of a thread per connection"))
         .get_matches();
     
     let engine_name  = matches.value_of("engine name")
@@ -57,31 +72,91 @@ fn main() -> Result<()> {
     let address   = matches.value_of("server address")
                 .unwrap();
 
-    if !judge_engine_flag(engine_name)? {
-        Err(KvsError::EngineError)?
-    }    
+    let options = KvStoreOptions {
+        sync: matches.value_of("sync policy")
+                .unwrap()
+                .parse()?,
+        codec: matches.value_of("log codec")
+                .unwrap()
+                .parse()?,
+        read_only: matches.is_present("read only"),
+        ..KvStoreOptions::default()
+    };
+    if options.read_only && engine_name == "sled" {
+        Err(KvsError::StringError("sled cannot be served read-only".to_owned()))?
+    }
+
+    let data_dir = PathBuf::from(matches.value_of("data dir")
+                .unwrap());
+    fs::create_dir_all(&data_dir)?;
+
+    if engine_name == "kvs" && matches.is_present("upgrade") {
+        info!("Upgrading the store format");
+        KvStore::upgrade(&data_dir, options.codec)?;
+    }
 
     info!("ENGINE: {}", engine_name);
+    info!("DATA DIR: {}", data_dir.display());
     info!("Serve {}", address);
 
-    run_with_name(address, engine_name)
+    let asynchronous = matches.is_present("async");
+    run_with_name(address, engine_name, &data_dir, options, asynchronous)
 }
 
-fn run_with_name(address: &str, engine_name: &str) -> Result<()> {
+fn run_with_name(address: &str, engine_name: &str, data_dir: &Path, options: KvStoreOptions, asynchronous: bool) -> Result<()> {
     match engine_name {
-        "kvs" => run(address, KvStore::new()?),
-        "sled" => run(address, SledKvsEngine::new(
-            sled::open(
-                current_dir()?
-            )?
-        )?),
-        _ => run(address, KvStore::new()?),
+        "kvs" => run(address, KvStore::open_with_options(data_dir, options)?, asynchronous),
+        "sled" => run(address, SledKvsEngine::open(data_dir)?, asynchronous),
+        _ => run(address, KvStore::open_with_options(data_dir, options)?, asynchronous),
     }
 }
 
-fn run<E: KvsEngine>(address: &str, e: E) -> Result<()> {
+fn run<E: KvsEngine>(address: &str, e: E, asynchronous: bool) -> Result<()> {
+    if asynchronous {
+        return tokio::runtime::Runtime::new()?.block_on(async {
+            let server = AsyncKvsServer::new(address, e).await?;
+            let shutdown = server.shutdown_handle();
+            tokio::spawn(async move {
+                match terminated().await {
+                    Ok(()) => shutdown.shutdown(),
+                    Err(e) => error!("cannot listen for signals: {}", e),
+                }
+            });
+            server.run().await
+        });
+    }
+
     let pool = NaiveThreadPool::new(0)?;
 
     let server = KvsServer::new(address,e, pool)?;
+    let shutdown = server.shutdown_handle();
+    let signals = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    thread::spawn(move || {
+        match signals.block_on(terminated()) {
+            Ok(()) => shutdown.shutdown(),
+            Err(e) => error!("cannot listen for signals: {}", e),
+        }
+    });
     server.run()
+}
+
+/// Resolves once the process is asked to stop with SIGINT or SIGTERM.
+async fn terminated() -> Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut term = signal(SignalKind::terminate())?;
+        tokio::select! {
+            res = tokio::signal::ctrl_c() => res?,
+            _ = term.recv() => {},
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+
+    info!("Signalled to stop");
+    Ok(())
 }
\ No newline at end of file
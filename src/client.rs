@@ -1,60 +1,238 @@
+use std::collections::HashMap;
+use std::future::Future;
 use std::net::SocketAddr;
+use std::ops::Bound;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Duration;
 
 use tokio::io::{BufWriter, BufReader};
 use tokio::io::{WriteHalf, ReadHalf};
 use tokio::net::TcpStream;
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 
+use crate::engine::{KvPair, Versioned, WriteBatch, prefix_range};
 use crate::err::*;
 use crate::protocol::*;
 
+/// Requests sent but not answered yet, by id, or `None` once the
+/// connection is closed.
+type Waiters = Arc<Mutex<Option<HashMap<u64, oneshot::Sender<Response>>>>>;
+
+/// A connection to a `KvsServer`, kept open across requests.
+///
+/// Requests can be pipelined: `send` returns as soon as a request is
+/// written, and a background task hands each response to the request of
+/// the same id.
 pub struct KvsClient {
     writer : BufWriter<WriteHalf<TcpStream>>,
-    reader : BufReader<ReadHalf<TcpStream>>,
+    waiters: Waiters,
+    next_id: u64,
+    dispatcher: JoinHandle<()>,
+}
+
+/// The response to a request sent with `KvsClient::send`.
+pub struct PendingResponse(oneshot::Receiver<Response>);
+
+impl Future for PendingResponse {
+    type Output = Result<Response>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut self.0).poll(cx).map(|res| res.map_err(|_| {
+            KvsError::StringError("connection closed before the response".to_owned())
+        }))
+    }
 }
 
 impl KvsClient {
     pub async fn new(address: SocketAddr) -> Result<Self> {
         let stream = TcpStream::connect(address).await?;
         let (reader, writer) = tokio::io::split(stream);
-        let reader = BufReader::new(reader);
         let writer = BufWriter::new(writer);
+        let waiters = Arc::new(Mutex::new(Some(HashMap::new())));
+        let dispatcher = tokio::spawn(dispatch(BufReader::new(reader), waiters.clone()));
         Ok( Self {
             writer,
-            reader
+            waiters,
+            next_id: 0,
+            dispatcher,
         })
     }
 
-    pub async fn set(&mut self, key: String, value: String) -> Result<()> {
+    /// Sends `request` without waiting for the server, returning its
+    /// response to await later.
+    pub async fn send(&mut self, request: Request) -> Result<PendingResponse> {
+        let id = self.next_id;
+        self.next_id += 1;
+        let (tx, rx) = oneshot::channel();
+        match self.waiters.lock().unwrap().as_mut() {
+            Some(waiters) => waiters.insert(id, tx),
+            None => return Err(KvsError::StringError("connection closed".to_owned())),
+        };
+        if let Err(e) = request.write(id, &mut self.writer).await {
+            if let Some(waiters) = self.waiters.lock().unwrap().as_mut() {
+                waiters.remove(&id);
+            }
+            return Err(e);
+        }
+        Ok(PendingResponse(rx))
+    }
+
+    pub async fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
         let op = Request::Set(key, value);
-        op.write(&mut self.writer).await?;
-        match Response::read_from(&mut self.reader).await? {
+        match self.send(op).await?.await? {
             Response::Ok => {
                 Ok(())
             },
-            Response::Error(e) => Err(KvsError::StringError(e)),
+            Response::Error(code, message) => Err(code.into_error(message)),
             _ => Err(KvsError::StringError("Illegel response".to_string())),
         }
     }
 
-    pub async fn get(&mut self, key: String) -> Result<Option<String>> {
+    pub async fn set_with_ttl(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let op = Request::SetWithTtl(key, value, ttl);
+        match self.send(op).await?.await? {
+            Response::Ok => Ok(()),
+            Response::Error(code, message) => Err(code.into_error(message)),
+            _ => Err(KvsError::StringError("Illegel response".to_string())),
+        }
+    }
+
+    pub async fn expire(&mut self, key: Vec<u8>, ttl: Duration) -> Result<()> {
+        let op = Request::Expire(key, ttl);
+        match self.send(op).await?.await? {
+            Response::Ok => Ok(()),
+            Response::Error(code, message) => Err(code.into_error(message)),
+            _ => Err(KvsError::StringError("Illegel response".to_string())),
+        }
+    }
+
+    pub async fn get(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
         let op = Request::Get(key);
-        op.write(&mut self.writer).await?;
+        match self.send(op).await?.await? {
+            Response::Get(v) => Ok(v),
+            Response::Error(code, message) => Err(code.into_error(message)),
+            _ => Err(KvsError::StringError("Illegel response".to_string())),
+        }
+    }
+
+    pub async fn get_versioned(&mut self, key: Vec<u8>) -> Result<Option<Versioned>> {
+        let op = Request::GetVersioned(key);
+        match self.send(op).await?.await? {
+            Response::GetVersioned(v) => Ok(v),
+            Response::Error(code, message) => Err(code.into_error(message)),
+            _ => Err(KvsError::StringError("Illegel response".to_string())),
+        }
+    }
 
-        match Response::read_from(&mut self.reader).await? {
+    pub async fn get_range(&mut self, key: Vec<u8>, offset: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        let op = Request::GetRange(key, offset, len);
+        match self.send(op).await?.await? {
             Response::Get(v) => Ok(v),
-            Response::Error(e) => Err(KvsError::StringError(e)),
+            Response::Error(code, message) => Err(code.into_error(message)),
             _ => Err(KvsError::StringError("Illegel response".to_string())),
         }
     }
 
-    pub async fn remove(&mut self, key: String) -> Result<()> {
+    pub async fn remove(&mut self, key: Vec<u8>) -> Result<()> {
         let op = Request::Remove(key);
-        op.write(&mut self.writer).await?;
+        match self.send(op).await?.await? {
+            Response::Ok => Ok(()),
+            Response::Error(code, message) => Err(code.into_error(message)),
+            _ => Err(KvsError::StringError("Illegel response".to_string())),
+        }
+    }
 
-        match Response::read_from(&mut self.reader).await? {
+    pub async fn compare_and_swap(&mut self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<()> {
+        let op = Request::CompareAndSwap(key, expected, new);
+        match self.send(op).await?.await? {
             Response::Ok => Ok(()),
-            Response::Error(e) => Err(KvsError::StringError(e)),
+            Response::Error(code, message) => Err(code.into_error(message)),
+            _ => Err(KvsError::StringError("Illegel response".to_string())),
+        }
+    }
+
+    pub async fn compare_version_and_swap(&mut self, key: Vec<u8>, version: Option<u64>, new: Option<Vec<u8>>) -> Result<Option<u64>> {
+        let op = Request::CompareVersionAndSwap(key, version, new);
+        match self.send(op).await?.await? {
+            Response::Version(version) => Ok(version),
+            Response::Error(code, message) => Err(code.into_error(message)),
             _ => Err(KvsError::StringError("Illegel response".to_string())),
         }
     }
-}
\ No newline at end of file
+
+    pub async fn incr(&mut self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        let op = Request::Incr(key, delta);
+        match self.send(op).await?.await? {
+            Response::Counter(n) => Ok(n),
+            Response::Error(code, message) => Err(code.into_error(message)),
+            _ => Err(KvsError::StringError("Illegel response".to_string())),
+        }
+    }
+
+    pub async fn decr(&mut self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        self.incr(key, delta.checked_neg().ok_or(KvsError::Overflow)?).await
+    }
+
+    pub async fn append(&mut self, key: Vec<u8>, suffix: Vec<u8>) -> Result<u64> {
+        let op = Request::Append(key, suffix);
+        match self.send(op).await?.await? {
+            Response::Length(len) => Ok(len),
+            Response::Error(code, message) => Err(code.into_error(message)),
+            _ => Err(KvsError::StringError("Illegel response".to_string())),
+        }
+    }
+
+    pub async fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        let op = Request::Batch(batch);
+        match self.send(op).await?.await? {
+            Response::Ok => Ok(()),
+            Response::Error(code, message) => Err(code.into_error(message)),
+            _ => Err(KvsError::StringError("Illegel response".to_string())),
+        }
+    }
+
+    pub async fn compact(&mut self) -> Result<()> {
+        let op = Request::Compact;
+        match self.send(op).await?.await? {
+            Response::Ok => Ok(()),
+            Response::Error(code, message) => Err(code.into_error(message)),
+            _ => Err(KvsError::StringError("Illegel response".to_string())),
+        }
+    }
+
+    pub async fn scan(&mut self, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>, limit: Option<usize>) -> Result<Vec<KvPair>> {
+        let op = Request::Scan(start, end, limit);
+        match self.send(op).await?.await? {
+            Response::Scan(pairs) => Ok(pairs),
+            Response::Error(code, message) => Err(code.into_error(message)),
+            _ => Err(KvsError::StringError("Illegel response".to_string())),
+        }
+    }
+
+    pub async fn prefix(&mut self, prefix: Vec<u8>, limit: Option<usize>) -> Result<Vec<KvPair>> {
+        let (start, end) = prefix_range(prefix);
+        self.scan(start, end, limit).await
+    }
+}
+
+impl Drop for KvsClient {
+    fn drop(&mut self) {
+        self.dispatcher.abort();
+    }
+}
+
+/// Hands each response read from the server to the request it answers.
+/// Once the connection ends, the requests still waiting are dropped and
+/// fail.
+async fn dispatch(mut reader: BufReader<ReadHalf<TcpStream>>, waiters: Waiters) {
+    while let Ok(Some((id, response))) = Response::read_from(&mut reader).await {
+        let tx = waiters.lock().unwrap().as_mut().and_then(|waiters| waiters.remove(&id));
+        if let Some(tx) = tx {
+            let _ = tx.send(response);
+        }
+    }
+    waiters.lock().unwrap().take();
+}
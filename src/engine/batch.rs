@@ -0,0 +1,49 @@
+use serde::{Serialize, Deserialize};
+
+/// A single operation of a `WriteBatch`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum BatchOp {
+    Set(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+}
+
+/// A group of sets and removes that an engine applies as one unit:
+/// after a crash either all of them are visible or none.
+///
+/// Removing a key that does not exist is not an error inside a batch.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set(&mut self, key: Vec<u8>, value: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::Set(key, value));
+        self
+    }
+
+    pub fn remove(&mut self, key: Vec<u8>) -> &mut Self {
+        self.ops.push(BatchOp::Remove(key));
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn into_ops(self) -> Vec<BatchOp> {
+        self.ops
+    }
+}
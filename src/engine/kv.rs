@@ -1,146 +1,762 @@
 use std::cell::RefCell;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::env::current_dir;
 use std::io::{SeekFrom, Write, BufWriter, Take};
 use std::fs::{File, OpenOptions, self};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::io::{Seek, BufReader, Read, self};
+use std::ops::RangeBounds;
+use std::str::FromStr;
 use std::sync::atomic::{AtomicU64,Ordering::SeqCst};
-use std::sync::{Arc, Mutex};
+use std::mem;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex, Weak};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 
+use fs2::FileExt;
+use log::{error, warn};
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
 use crossbeam_skiplist::SkipMap;
 
-use crate::engine::KvsEngine;
+use crate::engine::{KvsEngine, KvPair, BatchOp, Versioned, WriteBatch, KvStoreOptions, SyncPolicy};
+use crate::engine::{add_to_counter, expiry_after, now_millis};
+use crate::engine::manifest::{self, Manifest};
 use crate::err::*;
 
 #[derive(Serialize, Deserialize)]
 enum Entry {
+    Set(Vec<u8>, Vec<u8>),
+    Remove(Vec<u8>),
+    /// Header of a write batch; the next `n` entries belong to it and are
+    /// only replayed if all of them made it to the log.
+    Batch(u64),
+    /// A `Set` that expires at the given time, in milliseconds since the
+    /// Unix epoch.
+    SetExpiring(Vec<u8>, Vec<u8>, u64),
+    /// A set with the sequence number of the write, which replaces `Set`
+    /// and `SetExpiring` from format version 3 on.
+    Put { key: Vec<u8>, value: Vec<u8>, seq: u64, expires_at: Option<u64> },
+    /// A remove with the sequence number of the write, which replaces
+    /// `Remove` from format version 3 on.
+    Delete { key: Vec<u8>, seq: u64 },
+    /// The last sequence number handed out when the log was started, so it
+    /// survives compactions dropping the entries that had it.
+    Sequence(u64),
+    /// Appends `suffix` to the value whose last segment is the entry at
+    /// `prev_offset` of the same log, which is `base` bytes long so far.
+    Append {
+        key: Vec<u8>,
+        suffix: Vec<u8>,
+        seq: u64,
+        expires_at: Option<u64>,
+        base: u64,
+        prev_offset: u64,
+        prev_size: u64,
+    },
+}
+
+impl Entry {
+    fn expires_at(&self) -> Option<u64> {
+        match self {
+            Entry::SetExpiring(.., at) => Some(*at),
+            Entry::Put { expires_at, .. } | Entry::Append { expires_at, .. } => *expires_at,
+            _ => None,
+        }
+    }
+
+    /// The sequence number of the write, 0 for entries that predate them.
+    fn seq(&self) -> u64 {
+        match self {
+            Entry::Put { seq, .. }
+            | Entry::Delete { seq, .. }
+            | Entry::Append { seq, .. }
+            | Entry::Sequence(seq) => *seq,
+            _ => 0,
+        }
+    }
+}
+
+/// How entries and hints are serialized inside their frames. It is picked
+/// when a store is created and recorded in its manifest, as the logs can only
+/// be replayed with the codec they were written with.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum LogCodec {
+    /// serde_json, which spells out every byte of a value as a number.
+    Json,
+    /// bincode, which stores values as they are.
+    Bincode,
+}
+
+impl LogCodec {
+    fn encode<T: Serialize>(self, v: &T) -> Result<Vec<u8>> {
+        match self {
+            LogCodec::Json => Ok(serde_json::to_vec(v)?),
+            LogCodec::Bincode => Ok(bincode::serialize(v)?),
+        }
+    }
+
+    fn decode<T: DeserializeOwned>(self, buf: &[u8]) -> Option<T> {
+        match self {
+            LogCodec::Json => serde_json::from_slice(buf).ok(),
+            LogCodec::Bincode => bincode::deserialize(buf).ok(),
+        }
+    }
+}
+
+impl FromStr for LogCodec {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(LogCodec::Json),
+            "bincode" => Ok(LogCodec::Bincode),
+            _ => Err(KvsError::StringError(format!("invalid log codec {}", s))),
+        }
+    }
+}
+
+const ENGINE_NAME: &str = "kvs";
+const LOCK_NAME: &str = "LOCK";
+
+/// Version of the on-disk format written by this build. Version 0 is the
+/// original format, which had no manifest and unframed JSON entries with
+/// string keys and values. Version 2 added expiring entries, version 3
+/// sequence numbers and version 4 appends, so stores from version 1 on are
+/// read as they are.
+const FORMAT_VERSION: u32 = 4;
+
+/// Appends chained to a value before the next one rewrites it whole, which
+/// bounds the entries a read of the value goes through.
+const MAX_CHAIN_LEN: u32 = 32;
+
+/// How long writes wait before starting a compaction again after one
+/// failed. An explicit `compact` retries right away.
+const COMPACTION_RETRY_DELAY: Duration = Duration::from_secs(60);
+
+/// An entry of a version 0 log.
+#[derive(Deserialize)]
+enum LegacyEntry {
     Set(String, String),
     Remove(String),
 }
 
+impl From<LegacyEntry> for Entry {
+    fn from(e: LegacyEntry) -> Self {
+        match e {
+            LegacyEntry::Set(k, v) => Entry::Set(k.into_bytes(), v.into_bytes()),
+            LegacyEntry::Remove(k) => Entry::Remove(k.into_bytes()),
+        }
+    }
+}
+
+/// Where a live entry of a compacted log sits, so opening the store can
+/// index the log from its `N.hint` file instead of replaying it.
+#[derive(Serialize, Deserialize)]
+struct Hint {
+    key: Vec<u8>,
+    offset: u64,
+    size: u64,
+    #[serde(default)]
+    expires_at: Option<u64>,
+    #[serde(default)]
+    seq: u64,
+}
+
+/// Entries and hints are framed as `len: u32 | crc32: u32 | payload` (little-endian),
+/// the checksum being taken over the serialized payload.
+const FRAME_HEADER_SIZE: usize = 8;
+
+#[derive(Clone, Copy, PartialEq)]
 struct Position {
     log_no: u64, 
     offset: u64,
-    size: u64
+    size: u64,
+    /// When the entry expires, kept here so expired keys can be skipped
+    /// without reading them.
+    expires_at: Option<u64>,
+    /// Sequence number of the write, the version of the value.
+    seq: u64,
+    /// Number and total size of the earlier segments of an appended value,
+    /// which all sit in the same log.
+    chain_len: u32,
+    chain_size: u64,
 }
 
-const MAX_SIZE : u64 = 1024 * 1024;
+impl Position {
+    fn expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|at| at <= now)
+    }
+
+    /// Bytes taken by the value in its log, counting all its segments.
+    fn total_size(&self) -> u64 {
+        self.size + self.chain_size
+    }
+
+    /// Whether `self` is an append to the value at `prev`.
+    fn chains(&self, prev: &Position) -> bool {
+        self.chain_len > 0 && self.log_no == prev.log_no && self.chain_size == prev.total_size()
+    }
+
+    /// The previous segment of an appended value.
+    fn prev(&self, offset: u64, size: u64) -> Position {
+        Position { offset, size, chain_len: 0, chain_size: 0, ..*self }
+    }
+}
+
+/// Bytes written to a log, and how many of them are no longer needed.
+#[derive(Clone, Copy, Default)]
+struct LogStats {
+    size: u64,
+    garbage: u64,
+}
 
 pub struct KvStore {
     workdir: Arc<PathBuf>,
     reader: ReadModule,
     writer: Arc<Mutex<WriteModule>>,
-    map: Arc<SkipMap<String, Position>>,
+    pending: Arc<Mutex<Vec<PendingWrite>>>,
+    map: Arc<SkipMap<Vec<u8>, Position>>,
+    read_only: bool,
 }
 
+enum WriteOp {
+    Set(Vec<u8>, Vec<u8>),
+    /// A set expiring at the given time.
+    SetWithTtl(Vec<u8>, Vec<u8>, u64),
+    /// Rewrites the current value to expire at the given time.
+    Expire(Vec<u8>, u64),
+    Remove(Vec<u8>),
+    /// A set, or a remove if there is no new value, of a key currently
+    /// holding the expected value.
+    CompareAndSwap(Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>),
+    /// A `CompareAndSwap` going by the version of the current value.
+    CompareVersionAndSwap(Vec<u8>, Option<u64>, Option<Vec<u8>>),
+    /// Adds to the counter under a key, keeping its expiry time.
+    Incr(Vec<u8>, i64),
+    /// Appends to the value under a key, keeping its expiry time.
+    Append(Vec<u8>, Vec<u8>),
+    Batch(WriteBatch),
+}
+
+/// A write waiting for the next group commit, with where to send its result.
+type PendingWrite = (WriteOp, Sender<Result<Outcome>>);
+
+/// What a committed write reports back to its writer.
+#[derive(Default)]
+struct Outcome {
+    /// The version it gave its last key, `None` if it removed it.
+    version: Option<u64>,
+    /// The value an `Incr` left.
+    counter: Option<i64>,
+    /// The length of the value an `Append` left.
+    len: Option<u64>,
+}
+
+/// The new position of a key once a write is committed, `None` if removed.
+type IndexUpdate = (Vec<u8>, Option<Position>);
+
+/// Index updates of the writes earlier in a group, not published yet.
+type Overlay = HashMap<Vec<u8>, Option<Position>>;
+
+/// Entry count and header of a batch being replayed, with its entries so far.
+type PendingBatch = (u64, Position, Vec<(Entry, Position)>);
+
 struct ReadModule {
     readers: RefCell<BTreeMap<u64, ReadSeeker<File>>>,
     newest_index: Arc<AtomicU64>, 
-    workdir: Arc<PathBuf>
+    workdir: Arc<PathBuf>,
+    codec: LogCodec,
 }
 
 impl ReadModule {
-    fn new(newest_index : Arc<AtomicU64>, workdir: Arc<PathBuf>) -> Self {
+    fn new(newest_index : Arc<AtomicU64>, workdir: Arc<PathBuf>, codec: LogCodec) -> Self {
         Self {
             readers: RefCell::new(BTreeMap::new()),
             newest_index,
             workdir,
+            codec,
         }
     }
 }
 
 struct WriteModule {
     reader: ReadModule,
-    map: Arc<SkipMap<String, Position>>,
-    writer: WriteSeeker<File>,
+    map: Arc<SkipMap<Vec<u8>, Position>>,
+    /// The current log, `None` for read-only stores.
+    writer: Option<WriteSeeker<File>>,
+    /// Holds the directory lock for as long as the store is open.
+    lock: Option<File>,
     index: u64,
     workdir: Arc<PathBuf>,
-    compact_size: u64,
+    stats: BTreeMap<u64, LogStats>,
+    options: KvStoreOptions,
+    // writes flushed to the OS but not yet synced to disk
+    unsynced: u64,
+    last_sync: Instant,
+    compacting: bool,
+    compaction: Option<JoinHandle<Result<()>>>,
+    /// Set when a compaction fails, to when writes may start another.
+    retry_compaction_at: Option<Instant>,
+    /// Expiry times of the indexed keys, soonest first. Entries go stale
+    /// when keys are rewritten, and are skipped once they come up.
+    expiries: BTreeSet<(u64, Vec<u8>)>,
+    /// The last sequence number handed out.
+    seq: u64,
 }
 
 impl KvsEngine for KvStore {
-    fn set(&self, k: String, v: String) -> Result<()> {
-        self.writer.lock().unwrap().set(k, v)
+    fn set(&self, k: Vec<u8>, v: Vec<u8>) -> Result<()> {
+        self.submit(WriteOp::Set(k, v))
     }
 
-    fn get(&self, k: String) -> Result<Option<String>> {
+    fn set_with_ttl(&self, k: Vec<u8>, v: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.submit(WriteOp::SetWithTtl(k, v, expiry_after(ttl)))
+    }
+
+    fn expire(&self, k: Vec<u8>, ttl: Duration) -> Result<()> {
+        self.submit(WriteOp::Expire(k, expiry_after(ttl)))
+    }
+
+    fn compare_and_swap(&self, k: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<()> {
+        self.submit(WriteOp::CompareAndSwap(k, expected, new))
+    }
+
+    fn compare_version_and_swap(&self, k: Vec<u8>, version: Option<u64>, new: Option<Vec<u8>>) -> Result<Option<u64>> {
+        Ok(self.submit_for_outcome(WriteOp::CompareVersionAndSwap(k, version, new))?.version)
+    }
+
+    fn incr(&self, k: Vec<u8>, delta: i64) -> Result<i64> {
+        let outcome = self.submit_for_outcome(WriteOp::Incr(k, delta))?;
+        Ok(outcome.counter.unwrap_or_default())
+    }
+
+    fn get(&self, k: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.get_versioned(k)?.map(|(value, _)| value))
+    }
+
+    fn get_versioned(&self, k: Vec<u8>) -> Result<Option<Versioned>> {
         if let Some(pos) = self.map.get(&k) {
-            Ok (self.reader.read(pos.value())?)
+            Ok (self.read_at(&k, pos.value(), 0, u64::MAX)?)
         } else {
             Ok(None)
         }
     }
 
-    fn remove(&self, k: String) -> Result<()> {
-        self.writer.lock().unwrap().remove(k)
+    fn get_range(&self, k: Vec<u8>, offset: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        if let Some(pos) = self.map.get(&k) {
+            Ok (self.read_at(&k, pos.value(), offset, len)?.map(|(value, _)| value))
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn append(&self, k: Vec<u8>, suffix: Vec<u8>) -> Result<u64> {
+        let outcome = self.submit_for_outcome(WriteOp::Append(k, suffix))?;
+        Ok(outcome.len.unwrap_or_default())
+    }
+
+    fn remove(&self, k: Vec<u8>) -> Result<()> {
+        self.submit(WriteOp::Remove(k))
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        self.submit(WriteOp::Batch(batch))
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<Vec<KvPair>> {
+        let mut pairs = vec![];
+        let now = now_millis();
+        let live = self.map.range(range).filter(|e| !e.value().expired(now));
+        for e in live.take(limit.unwrap_or(usize::MAX)) {
+            if let Some((value, _)) = self.read_at(e.key(), e.value(), 0, u64::MAX)? {
+                pairs.push((e.key().clone(), value));
+            }
+        }
+        Ok(pairs)
+    }
+
+    fn compact(&self) -> Result<()> {
+        if self.read_only {
+            Err(KvsError::ReadOnly)?
+        }
+        loop {
+            let mut writer = self.writer.lock().unwrap();
+            if let Some(running) = writer.compaction.take() {
+                // let a compaction started by the writes finish first, it
+                // leaves out whatever was written while it ran
+                drop(writer);
+                let _ = running.join();
+                continue;
+            }
+            if writer.compacting {
+                // another caller is already waiting on it
+                drop(writer);
+                thread::sleep(Duration::from_millis(1));
+                continue;
+            }
+
+            self.spawn_compaction(&mut writer)?;
+            let compaction = writer.compaction.take().unwrap();
+            drop(writer);
+            return compaction.join()
+                .unwrap_or_else(|_| Err(KvsError::StringError("compaction panicked".to_owned())));
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        if self.read_only {
+            return Ok(());
+        }
+        let mut writer = self.writer.lock().unwrap();
+        if writer.unsynced > 0 {
+            writer.sync()?;
+        }
+        Ok(())
     }
 }
 
 impl WriteModule {
-    fn set(&mut self, k: String, v: String) -> Result<()> {
-        let e = Entry::Set(k.clone(), v);
+    /// Appends a group of queued writes, commits them with a single flush
+    /// (and sync, if the policy asks for one), then publishes them to the
+    /// index and answers every writer.
+    fn write_group(&mut self, group: Vec<PendingWrite>) {
+        let writes = group.len() as u64;
+        // keys touched earlier in the group that are not in the index yet
+        let mut overlay = Overlay::new();
+        let mut appended = Vec::with_capacity(group.len());
+        for (op, tx) in group {
+            let updates = match op {
+                WriteOp::Incr(k, delta) => self.append_incr(k, delta, &overlay)
+                    .map(|(updates, n)| (updates, Outcome { counter: Some(n), ..Outcome::default() })),
+                WriteOp::Append(k, suffix) => self.append_suffix(k, suffix, &overlay)
+                    .map(|(updates, len)| (updates, Outcome { len: Some(len), ..Outcome::default() })),
+                op => self.append_op(op, &overlay).map(|updates| (updates, Outcome::default())),
+            };
+            if let Ok((updates, _)) = &updates {
+                for (k, pos) in updates {
+                    overlay.insert(k.clone(), *pos);
+                }
+            }
+            appended.push((updates, tx));
+        }
 
-        let offset = self.writer.pos as u64;
-        serde_json::to_writer(&mut self.writer, &e)?;
-        self.writer.flush()?;
-        let end = self.writer.pos as u64;
-        if let Some(old_pos) = self.map.get(&k) {
-            self.compact_size += old_pos.value().size;
+        let committed = self.commit(writes);
+        for (updates, tx) in appended {
+            let res = match (&committed, updates) {
+                (Err(e), _) => Err(KvsError::StringError(format!("group commit failed: {}", e))),
+                (Ok(()), Ok((updates, outcome))) => {
+                    let version = updates.last().and_then(|(_, pos)| pos.map(|pos| pos.seq));
+                    for (k, pos) in updates {
+                        self.publish(k, pos);
+                    }
+                    Ok(Outcome { version, ..outcome })
+                },
+                (Ok(()), Err(e)) => Err(e),
+            };
+            // the writer may have given up waiting, nothing to do then
+            let _ = tx.send(res);
         }
 
-        self.map.insert(k, Position{
-            log_no: self.index,
-            offset,
-            size: end - offset,
-        });
+    }
 
-        // if size overflowed
-        if self.compact_size > MAX_SIZE {
-            self.compact()?;
+    fn append_op(&mut self, op: WriteOp, overlay: &Overlay) -> Result<Vec<IndexUpdate>> {
+        match op {
+            WriteOp::Set(k, v) => self.put(k, v, None),
+            WriteOp::SetWithTtl(k, v, at) => self.put(k, v, Some(at)),
+            WriteOp::Expire(k, at) => {
+                let old = self.current(&k, overlay).ok_or(KvsError::NoEntryError)?;
+                let v = self.read_current(&old)?;
+                self.put(k, v, Some(at))
+            },
+            WriteOp::Remove(k) => {
+                if self.current(&k, overlay).is_none() {
+                    Err(KvsError::NoEntryError)?
+                }
+                self.delete(k)
+            },
+            WriteOp::CompareAndSwap(k, expected, new) => {
+                let current = match self.current(&k, overlay) {
+                    Some(pos) => Some(self.read_current(&pos)?),
+                    None => None,
+                };
+                if current != expected {
+                    Err(KvsError::Conflict)?
+                }
+                self.swap(k, current.is_some(), new)
+            },
+            WriteOp::CompareVersionAndSwap(k, version, new) => {
+                let current = self.current(&k, overlay);
+                if current.map(|pos| pos.seq) != version {
+                    Err(KvsError::Conflict)?
+                }
+                self.swap(k, current.is_some(), new)
+            },
+            WriteOp::Incr(k, delta) => Ok(self.append_incr(k, delta, overlay)?.0),
+            WriteOp::Append(k, suffix) => Ok(self.append_suffix(k, suffix, overlay)?.0),
+            WriteOp::Batch(batch) => {
+                if batch.is_empty() {
+                    return Ok(vec![]);
+                }
+                // staged whole before any of it is written, so a failure cannot
+                // leave part of it in the log for later writes to complete
+                let mut staged = Vec::new();
+                let header = self.stage(&mut staged, &Entry::Batch(batch.len() as u64))?;
+                let mut garbage = vec![header];
+                let mut updates = Vec::with_capacity(batch.len());
+                for op in batch.into_ops() {
+                    self.seq += 1;
+                    match op {
+                        BatchOp::Set(k, v) => {
+                            let e = Entry::Put { key: k.clone(), value: v, seq: self.seq, expires_at: None };
+                            updates.push((k, Some(self.stage(&mut staged, &e)?)));
+                        },
+                        BatchOp::Remove(k) => {
+                            let e = Entry::Delete { key: k.clone(), seq: self.seq };
+                            garbage.push(self.stage(&mut staged, &e)?);
+                            updates.push((k, None));
+                        },
+                    }
+                }
+                self.write_staged(&staged)?;
+                for pos in garbage {
+                    self.add_garbage(&pos);
+                }
+                Ok(updates)
+            },
         }
+    }
 
-        Ok(())
+    /// Appends the counter under `k` with `delta` added, and returns it.
+    fn append_incr(&mut self, k: Vec<u8>, delta: i64, overlay: &Overlay) -> Result<(Vec<IndexUpdate>, i64)> {
+        let (current, expires_at) = match self.current(&k, overlay) {
+            Some(pos) => (Some(self.read_current(&pos)?), pos.expires_at),
+            None => (None, None),
+        };
+        let n = add_to_counter(current.as_deref(), delta)?;
+        Ok((self.put(k, n.to_string().into_bytes(), expires_at)?, n))
     }
 
-    fn remove(&mut self, k: String) -> Result<()> {
-        let e = Entry::Remove(k.clone());
-        
-        serde_json::to_writer(&mut self.writer, &e)?;
-        self.writer.flush()?;
-        
-        if let None = self.map.remove(&k) {
-            Err(KvsError::NoEntryError)?
+    /// Appends `suffix` to the value under `k`, and returns its new length.
+    /// The suffix is chained to the value when that sits in the current log,
+    /// which saves rewriting it; otherwise the whole value is written anew.
+    fn append_suffix(&mut self, k: Vec<u8>, suffix: Vec<u8>, overlay: &Overlay) -> Result<(Vec<IndexUpdate>, u64)> {
+        let prev = match self.current(&k, overlay) {
+            Some(prev) => prev,
+            None => {
+                let len = suffix.len() as u64;
+                return Ok((self.put(k, suffix, None)?, len));
+            },
+        };
+        if prev.log_no != self.index || prev.chain_len >= MAX_CHAIN_LEN {
+            let mut value = self.read_current(&prev)?;
+            value.extend_from_slice(&suffix);
+            let len = value.len() as u64;
+            return Ok((self.put(k, value, prev.expires_at)?, len));
+        }
+
+        self.log()?.flush()?;
+        let base = self.reader.value_len(&prev)?;
+        let len = base + suffix.len() as u64;
+        self.seq += 1;
+        let mut pos = self.append(&Entry::Append {
+            key: k.clone(),
+            suffix,
+            seq: self.seq,
+            expires_at: prev.expires_at,
+            base,
+            prev_offset: prev.offset,
+            prev_size: prev.size,
+        })?;
+        pos.chain_len = prev.chain_len + 1;
+        pos.chain_size = prev.total_size();
+        Ok((vec![(k, Some(pos))], len))
+    }
+
+    /// Appends a set of `k` under the next sequence number.
+    fn put(&mut self, k: Vec<u8>, v: Vec<u8>, expires_at: Option<u64>) -> Result<Vec<IndexUpdate>> {
+        self.seq += 1;
+        let pos = self.append(&Entry::Put { key: k.clone(), value: v, seq: self.seq, expires_at })?;
+        Ok(vec![(k, Some(pos))])
+    }
+
+    /// Appends a tombstone for `k` under the next sequence number.
+    fn delete(&mut self, k: Vec<u8>) -> Result<Vec<IndexUpdate>> {
+        self.seq += 1;
+        let tombstone = self.append(&Entry::Delete { key: k.clone(), seq: self.seq })?;
+        self.add_garbage(&tombstone);
+        Ok(vec![(k, None)])
+    }
+
+    /// The write of a successful compare-and-swap on `k`, which is a remove
+    /// if there is no new value, and nothing at all if `k` has none either.
+    fn swap(&mut self, k: Vec<u8>, exists: bool, new: Option<Vec<u8>>) -> Result<Vec<IndexUpdate>> {
+        match new {
+            Some(v) => self.put(k, v, None),
+            None if exists => self.delete(k),
+            None => Ok(vec![]),
         }
+    }
 
+    /// Hands the appended entries to the OS, and to the disk when the sync
+    /// policy asks for it.
+    fn commit(&mut self, writes: u64) -> Result<()> {
+        self.log()?.flush()?;
+        self.unsynced += writes;
+        let due = match self.options.sync {
+            SyncPolicy::Never => false,
+            SyncPolicy::EveryWrite => true,
+            SyncPolicy::EveryN(n) => self.unsynced >= n,
+            SyncPolicy::Interval(d) => self.last_sync.elapsed() >= d,
+        };
+        if due {
+            self.sync()?;
+        }
         Ok(())
     }
 
-    fn compact(&mut self) -> Result<()> {
-        self.index += 1;
-        self.open_new_log()?;
-        let mut offset = 0;
-        for e in self.map.iter() {
-            let len = self.reader.read_and(&e.value(), |mut f| {
-                Ok(io::copy(&mut f, &mut self.writer)?)
-            })?;
-            self.map.insert(e.key().clone(), Position {
-                log_no: self.index,
-                offset,
-                size: len
-            });
-            offset += len;
+    fn log(&mut self) -> Result<&mut WriteSeeker<File>> {
+        self.writer.as_mut().ok_or(KvsError::ReadOnly)
+    }
+
+    fn sync(&mut self) -> Result<()> {
+        self.log()?.sync()?;
+        self.unsynced = 0;
+        self.last_sync = Instant::now();
+        Ok(())
+    }
+
+    /// Writes `e` at the end of the current log and returns where it landed.
+    fn append(&mut self, e: &Entry) -> Result<Position> {
+        let mut staged = Vec::new();
+        let pos = self.stage(&mut staged, e)?;
+        self.write_staged(&staged)?;
+        Ok(pos)
+    }
+
+    /// Adds the frame of `e` to `staged`, to be written at the end of the
+    /// current log by `write_staged`, and returns where it will land.
+    fn stage(&mut self, staged: &mut Vec<u8>, e: &Entry) -> Result<Position> {
+        let codec = self.options.codec;
+        let offset = self.log()?.pos as u64 + staged.len() as u64;
+        let start = staged.len();
+        write_frame(staged, codec, e)?;
+        Ok(Position {
+            log_no: self.index,
+            offset,
+            size: (staged.len() - start) as u64,
+            expires_at: e.expires_at(),
+            seq: e.seq(),
+            chain_len: 0,
+            chain_size: 0,
+        })
+    }
+
+    fn write_staged(&mut self, staged: &[u8]) -> Result<()> {
+        self.log()?.write_all(staged)?;
+        self.stats.entry(self.index).or_default().size += staged.len() as u64;
+        Ok(())
+    }
+
+    /// Points `k` at its new position, or drops it from the index if it was
+    /// removed; whatever it pointed at before becomes garbage.
+    fn publish(&mut self, k: Vec<u8>, pos: Option<Position>) {
+        if let Some(old) = self.map.get(&k).map(|e| *e.value()) {
+            // an append keeps what it is chained to
+            if !pos.is_some_and(|pos| pos.chains(&old)) {
+                self.add_garbage(&old);
+            }
+        }
+        match pos {
+            Some(pos) => {
+                if let Some(at) = pos.expires_at {
+                    self.expiries.insert((at, k.clone()));
+                }
+                self.map.insert(k, pos);
+            },
+            None => {
+                self.map.remove(&k);
+            },
         }
+    }
 
-        self.writer.flush()?;
-        self.delete_old_logs()?;
+    /// Drops the keys that have expired by now from the index. Their entries
+    /// need no tombstone, as they carry their expiry with them.
+    fn sweep_expired(&mut self) {
+        let now = now_millis();
+        while self.expiries.first().is_some_and(|(at, _)| *at <= now) {
+            let (at, k) = self.expiries.pop_first().unwrap();
+            // the key may have been rewritten since
+            if self.map.get(&k).is_some_and(|e| e.value().expires_at == Some(at)) {
+                self.publish(k, None);
+            }
+        }
+    }
 
-        self.index += 1;
+    /// Where the live value of `k` is, counting the writes earlier in the
+    /// group, or `None` if it has none.
+    fn current(&self, k: &[u8], overlay: &Overlay) -> Option<Position> {
+        let pos = match overlay.get(k) {
+            Some(pos) => *pos,
+            None => self.map.get(k).map(|e| *e.value()),
+        };
+        pos.filter(|pos| !pos.expired(now_millis()))
+    }
+
+    /// Reads the value at `pos`, which may still sit in the write buffer.
+    fn read_current(&mut self, pos: &Position) -> Result<Vec<u8>> {
+        if pos.log_no == self.index {
+            self.log()?.flush()?;
+        }
+        self.reader.read(pos)?.ok_or(KvsError::NoEntryError)
+    }
+
+    fn add_garbage(&mut self, pos: &Position) {
+        self.stats.entry(pos.log_no).or_default().garbage += pos.total_size();
+    }
+
+    /// Whether writes should start a compaction now.
+    fn wants_compaction(&self) -> bool {
+        !self.compacting
+            && self.retry_compaction_at.is_none_or(|at| Instant::now() >= at)
+            && self.needs_compaction()
+    }
+
+    fn needs_compaction(&self) -> bool {
+        let (size, garbage) = self.stats.values()
+                .fold((0, 0), |(size, garbage), s| (size + s.size, garbage + s.garbage));
+        garbage > self.options.compaction_threshold
+            || self.options.compaction_ratio
+                .is_some_and(|ratio| size > 0 && garbage as f64 / size as f64 > ratio)
+    }
+
+    /// Seals the current log and moves writes to a fresh one, keeping the
+    /// log number in between for the compacted copy of everything sealed.
+    fn start_compaction(&mut self) -> Result<u64> {
+        let compact_no = self.index + 1;
+        self.index += 2;
         self.open_new_log()?;
-        
-        self.compact_size = 0;
-        Ok(())
+        // the compacted log drops tombstones, which may hold the last sequence numbers
+        let marker = self.append(&Entry::Sequence(self.seq))?;
+        self.add_garbage(&marker);
+        self.commit(1)?;
+        self.compacting = true;
+        Ok(compact_no)
+    }
+
+    /// Called with the index already pointing into the compacted log, whose
+    /// entries rewritten during the copy are garbage from the start.
+    /// The compaction is done by then, so old logs that cannot be deleted
+    /// are left for the next one.
+    fn finish_compaction(&mut self, compact_no: u64, stats: LogStats) {
+        self.compacting = false;
+        self.retry_compaction_at = None;
+        self.stats = self.stats.split_off(&compact_no);
+        self.stats.insert(compact_no, stats);
+        if let Err(e) = self.delete_old_logs(compact_no) {
+            error!("deleting the logs below {} failed: {}", get_log_name(compact_no), e);
+        }
     }
 
     fn open_new_log(&mut self) -> Result<()> {
@@ -156,18 +772,23 @@ impl WriteModule {
                     .read(true)
                     .open(file_path.as_path())?);
 
-        self.writer = writer;
+        self.writer = Some(writer);
         self.reader.readers.borrow_mut().insert(self.index, reader);
 
         Ok(())
     }
 
-    fn delete_old_logs(&self) -> Result<()> {
+    /// Deletes the logs below `below` oldest first, so that a tombstone is
+    /// never gone while an older value it hides is left behind.
+    fn delete_old_logs(&self, below: u64) -> Result<()> {
+        // stop readers from reaching for the old logs first
+        (*self.reader.newest_index).store(below, SeqCst);
+
         let logs = get_log_numbers(self.workdir.to_path_buf())?;
         let mut delete_files = vec![];
 
         for log in logs.iter() {
-            if *log < self.index {
+            if *log < below {
                 let log_name = get_log_name(*log);
                 self.reader.readers.borrow_mut().remove(log);
                 let mut log_path= (*self.workdir).clone();
@@ -178,22 +799,64 @@ impl WriteModule {
 
         for f in delete_files {
             fs::remove_file(f.as_path())?;
+            // only compacted logs have hints
+            match fs::remove_file(f.with_extension("hint")) {
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+                res => res?,
+            }
         }
 
-        (*self.reader.newest_index).store(self.index, SeqCst);
-
         Ok(())
     }
 }
 
 impl ReadModule {
-    fn read(&self, pos: &Position) -> Result<Option<String>> {
-        self.read_and(pos, |f| {
-            if let Entry::Set(..,value) = serde_json::from_reader(f)? {
-                Ok(Some(value))
-            } else {
-                Ok(None)
+    fn read(&self, pos: &Position) -> Result<Option<Vec<u8>>> {
+        self.read_range(pos, 0, u64::MAX)
+    }
+
+    /// Reads `len` bytes of the value at `pos` from `offset` on, or as many
+    /// as there are. Of an appended value, only the segments holding some
+    /// of them are read, going back from the last one.
+    fn read_range(&self, pos: &Position, offset: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        let end = offset.saturating_add(len);
+        let mut pieces = vec![];
+        let mut segment = *pos;
+        loop {
+            match self.read_entry(&segment)? {
+                Entry::Append { suffix, base, prev_offset, prev_size, .. } => {
+                    pieces.push(slice(suffix, offset.saturating_sub(base), end.saturating_sub(base)));
+                    if offset >= base {
+                        break;
+                    }
+                    segment = segment.prev(prev_offset, prev_size);
+                },
+                Entry::Set(_, value) | Entry::SetExpiring(_, value, _) | Entry::Put { value, .. } => {
+                    pieces.push(slice(value, offset, end));
+                    break;
+                },
+                _ if segment == *pos => return Ok(None),
+                _ => Err(KvsError::Corruption { log_no: segment.log_no, offset: segment.offset })?,
             }
+        }
+        Ok(Some(pieces.into_iter().rev().flatten().collect()))
+    }
+
+    /// The length of the value at `pos`, read off its last segment.
+    fn value_len(&self, pos: &Position) -> Result<u64> {
+        match self.read_entry(pos)? {
+            Entry::Append { suffix, base, .. } => Ok(base + suffix.len() as u64),
+            Entry::Set(_, value) | Entry::SetExpiring(_, value, _) | Entry::Put { value, .. } => {
+                Ok(value.len() as u64)
+            },
+            _ => Err(KvsError::Corruption { log_no: pos.log_no, offset: pos.offset }),
+        }
+    }
+
+    fn read_entry(&self, pos: &Position) -> Result<Entry> {
+        self.read_and(pos, |mut f| {
+            read_frame(&mut f, self.codec, pos.log_no, pos.offset)?
+                .ok_or(KvsError::Corruption { log_no: pos.log_no, offset: pos.offset })
         })
     }
 
@@ -246,9 +909,12 @@ impl Clone for KvStore {
                 readers: RefCell::new(BTreeMap::new()),
                 newest_index: Arc::clone(&self.reader.newest_index),
                 workdir: Arc::clone(&self.reader.workdir),
+                codec: self.reader.codec,
             }, 
             writer: self.writer.clone(),
+            pending: Arc::clone(&self.pending),
             map: Arc::clone(&self.map), 
+            read_only: self.read_only,
         }
     }
 }
@@ -259,75 +925,694 @@ impl KvStore {
     }
 
     pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with_options(path, KvStoreOptions::default())
+    }
+
+    /// Opens the store at `path` for reading only, see
+    /// `KvStoreOptions::read_only`. Nothing in the directory is created or
+    /// changed, not even a torn log tail.
+    pub fn open_read_only(path: impl Into<PathBuf>) -> Result<KvStore> {
+        let options = KvStoreOptions { read_only: true, ..KvStoreOptions::default() };
+        KvStore::open_with_options(path, options)
+    }
+
+    /// Opens the store at `path`, creating it if needed. `options.codec`
+    /// only applies to new stores, existing ones keep the codec in their manifest.
+    ///
+    /// Fails with `KvsError::Locked` if another store has the directory open
+    /// for writing, unless `options.read_only` is set.
+    pub fn open_with_options(path: impl Into<PathBuf>, mut options: KvStoreOptions) -> Result<KvStore> {
         let file_path : PathBuf = path.into();
+        let read_only = options.read_only;
+        // another engine's directory is turned away before a lock file lands in it
+        check_engine(&file_path)?;
+        let lock = if read_only { None } else { Some(lock_dir(&file_path)?) };
         let logs = get_log_numbers(file_path.clone())?;
+        let codec = match Manifest::load(&file_path)? {
+            Some(manifest) => {
+                manifest.check(ENGINE_NAME, FORMAT_VERSION)?;
+                if !read_only {
+                    bump_manifest(&file_path, manifest.clone())?;
+                }
+                manifest.codec.unwrap_or(LogCodec::Json)
+            },
+            None => {
+                manifest::check_unmanifested(&file_path, ENGINE_NAME)?;
+                for log_no in logs.iter() {
+                    if is_legacy_log(&file_path, *log_no)? {
+                        Err(KvsError::OutdatedFormat(0))?
+                    }
+                }
+                // stores from before the manifest was introduced are all JSON,
+                // but an interrupted upgrade may have left them in another codec
+                let codec = if logs.is_empty() {
+                    options.codec
+                } else {
+                    detect_codec(&file_path, &logs)?.unwrap_or(LogCodec::Json)
+                };
+                if !read_only {
+                    save_manifest(&file_path, codec)?;
+                }
+                codec
+            },
+        };
+        options.codec = codec;
         
-        let mut map = SkipMap::new();
-        let index = *logs.last().unwrap_or(&1);
-        for it in logs.iter() {
-            let mut log_file_path = file_path.clone();
-            log_file_path.push(get_log_name(*it));
-            let mut reader = ReadSeeker::new(OpenOptions::new()
-                        .read(true)
-                        .open(log_file_path)?);
-            
-            init_memory_a_file(&mut map, *it, &mut reader)?;
-        } 
-
-        let mut log_file = file_path.clone();
-        log_file.push(get_log_name(index));
+        let LoadedIndex { map, stats, seq, index } = load_index(&file_path, &logs, codec, read_only)?;
 
-        let writer = WriteSeeker::new(OpenOptions::new()
-                    .write(true)
-                    .truncate(false)
-                    .create(true)
-                    .open(current_dir().unwrap().join(log_file.clone()))?);
-        
+        let writer = if read_only {
+            None
+        } else {
+            let mut writer = WriteSeeker::new(OpenOptions::new()
+                        .write(true)
+                        .truncate(false)
+                        .create(true)
+                        .open(file_path.join(get_log_name(index)))?);
+            // keep appending to the newest log instead of overwriting it
+            writer.seek(SeekFrom::End(0))?;
+            Some(writer)
+        };
+
+        let expiries = map.iter()
+            .filter_map(|e| e.value().expires_at.map(|at| (at, e.key().clone())))
+            .collect();
         let ato_index = Arc::new(AtomicU64::new(index));
         let workdir = Arc::new(file_path);
         let map = Arc::new(map);
-        Ok(Self {
+        let sync = options.sync;
+        let sweep_interval = options.sweep_interval;
+        let store = Self {
             map: Arc::clone(&map),
             writer: Arc::new(Mutex::new(WriteModule {
                 writer,
+                lock,
                 index,
-                compact_size: 0,
-                reader: ReadModule::new(Arc::clone(&ato_index), Arc::clone(&workdir)),
+                stats,
+                reader: ReadModule::new(Arc::clone(&ato_index), Arc::clone(&workdir), codec),
                 map: Arc::clone(&map),
                 workdir: Arc::clone(&workdir),
+                options,
+                unsynced: 0,
+                last_sync: Instant::now(),
+                compacting: false,
+                compaction: None,
+                retry_compaction_at: None,
+                expiries,
+                seq,
             })),
-            reader: ReadModule::new(Arc::clone(&ato_index), Arc::clone(&workdir)),
+            pending: Arc::new(Mutex::new(vec![])),
+            reader: ReadModule::new(Arc::clone(&ato_index), Arc::clone(&workdir), codec),
             workdir: Arc::clone(&workdir),
-        })
+            read_only,
+        };
+
+        if !read_only {
+            if let SyncPolicy::Interval(interval) = sync {
+                spawn_syncer(Arc::downgrade(&store.writer), interval);
+            }
+            spawn_sweeper(Arc::downgrade(&store.writer), sweep_interval);
+        }
+        Ok(store)
     }
 }
 
-fn init_memory_a_file<R: Read + Seek + Sync>(map: &mut SkipMap<String, Position>, log_no: u64, reader: &mut ReadSeeker<R>) -> Result<()> {
-    reader.seek(SeekFrom::Start(0))?;
+/// The index replayed from the logs of a store.
+struct LoadedIndex {
+    map: SkipMap<Vec<u8>, Position>,
+    stats: BTreeMap<u64, LogStats>,
+    /// The last sequence number met.
+    seq: u64,
+    /// The newest log.
+    index: u64,
+}
+
+/// Replays `logs` into a fresh index, going by hint files where it can.
+/// Unless `read_only`, a torn tail of the newest log is cut off.
+fn load_index(file_path: &Path, logs: &[u64], codec: LogCodec, read_only: bool) -> Result<LoadedIndex> {
+    let mut map = SkipMap::new();
+    let mut stats = BTreeMap::new();
+    let mut seq = 0;
+    let index = *logs.last().unwrap_or(&1);
+    for it in logs.iter() {
+        // the newest log keeps growing, so it never goes by its hints
+        if *it < index && load_hints(&mut map, &mut stats, &mut seq, file_path, codec, *it)? {
+            continue;
+        }
+        let log_file_path = file_path.join(get_log_name(*it));
+        let mut reader = ReadSeeker::new(OpenOptions::new()
+                    .read(true)
+                    .open(&log_file_path)?);
+
+        let committed = init_memory_a_file(&mut map, &mut stats, &mut seq, codec, *it, &mut reader)?;
+        let len = fs::metadata(&log_file_path)?.len();
+        if committed < len {
+            warn!("{} has {} bytes of torn or unfinished writes at offset {}, discarding them",
+                get_log_name(*it), len - committed, committed);
+            // new writes are appended to the newest log, so they must not land after the garbage
+            if *it == index && !read_only {
+                OpenOptions::new()
+                    .write(true)
+                    .open(&log_file_path)?
+                    .set_len(committed)?;
+            }
+        }
+    }
+    Ok(LoadedIndex { map, stats, seq, index })
+}
+
+impl KvStore {
+    /// Rewrites a store left by an older version of kvs into the current
+    /// format, encoding its entries with `codec`. Stores in the current
+    /// format are left as they are.
+    ///
+    /// Logs are rewritten one by one and the manifest comes last, so an
+    /// interrupted upgrade is resumed by running it again. The logs it
+    /// already rewrote keep their codec, which the rest then take.
+    pub fn upgrade(path: impl Into<PathBuf>, codec: LogCodec) -> Result<()> {
+        let file_path: PathBuf = path.into();
+        check_engine(&file_path)?;
+        let _lock = lock_dir(&file_path)?;
+        if let Some(manifest) = Manifest::load(&file_path)? {
+            manifest.check(ENGINE_NAME, FORMAT_VERSION)?;
+            return bump_manifest(&file_path, manifest);
+        }
+        manifest::check_unmanifested(&file_path, ENGINE_NAME)?;
+
+        let logs = get_log_numbers(file_path.clone())?;
+        // logs already framed, by an interrupted upgrade or by a version from
+        // before the manifest was introduced, decide the codec of the rest
+        let codec = detect_codec(&file_path, &logs)?.unwrap_or(codec);
+        for log_no in logs.iter() {
+            if is_legacy_log(&file_path, *log_no)? {
+                upgrade_log(&file_path, codec, *log_no)?;
+            }
+        }
+        save_manifest(&file_path, codec)
+    }
 
-    let mut offset  = 0;
-    let mut stream = serde_json::Deserializer::from_reader(reader)
-        .into_iter::<Entry>();
-
-    while let Some(e) = stream.next() {
-        let new_pow = stream.byte_offset() as u64;
-        match e? {
-            Entry::Set(k1, _) => {
-                map.insert(k1, Position {
-                    log_no, 
-                    offset,
-                    size: new_pow  - offset,
-                });
+    fn submit(&self, op: WriteOp) -> Result<()> {
+        self.submit_for_outcome(op).map(|_| ())
+    }
+
+    /// Queues a write and waits for it to be committed. Whoever gets the
+    /// writer lock commits everything queued so far in one go, so writers
+    /// arriving while a commit is in progress share the next one.
+    fn submit_for_outcome(&self, op: WriteOp) -> Result<Outcome> {
+        if self.read_only {
+            Err(KvsError::ReadOnly)?
+        }
+        let (tx, rx) = channel();
+        self.pending.lock().unwrap().push((op, tx));
+        {
+            let mut writer = self.writer.lock().unwrap();
+            let group = mem::take(&mut *self.pending.lock().unwrap());
+            if !group.is_empty() {
+                writer.write_group(group);
+            }
+
+            // if size overflowed
+            if writer.wants_compaction() {
+                if let Err(e) = self.spawn_compaction(&mut writer) {
+                    error!("compaction failed: {}", e);
+                }
+            }
+        }
+        rx.recv().unwrap_or_else(|_| Err(KvsError::StringError("write was dropped".to_owned())))
+    }
+
+    /// Reads `len` bytes from `offset` on of the value at `pos`, along with
+    /// its version. A compaction may delete its log between the index lookup
+    /// and the read, in which case the index already points to the compacted
+    /// copy and the lookup is retried. Read-only stores first reload their
+    /// index, as the compaction ran in the writer next to them.
+    fn read_at(&self, k: &[u8], pos: &Position, offset: u64, len: u64) -> Result<Option<Versioned>> {
+        // expired keys stay in the index until the sweeper gets to them
+        if pos.expired(now_millis()) {
+            return Ok(None);
+        }
+        match self.reader.read_range(pos, offset, len) {
+            Err(KvsError::IOError(ref e)) if e.kind() == io::ErrorKind::NotFound => {
+                if self.read_only {
+                    self.reload()?;
+                }
+                match self.map.get(k) {
+                    Some(pos) if !pos.value().expired(now_millis()) => {
+                        Ok(self.reader.read_range(pos.value(), offset, len)?.map(|value| (value, pos.value().seq)))
+                    },
+                    Some(_) => Ok(None),
+                    None => Ok(None),
+                }
             },
-            Entry::Remove(k1) => {
-                map.remove(&k1);
+            res => Ok(res?.map(|value| (value, pos.seq))),
+        }
+    }
+
+    /// Replaces the index of a read-only store with one replayed from the
+    /// logs now in its directory. The writer may delete logs while they are
+    /// replayed, in which case it starts over.
+    fn reload(&self) -> Result<()> {
+        // one reload at a time, read-only stores have no other use for the lock
+        let _writer = self.writer.lock().unwrap();
+        let mut attempts = 0;
+        let (logs, loaded) = loop {
+            let logs = get_log_numbers((*self.workdir).clone())?;
+            match load_index(&self.workdir, &logs, self.reader.codec, true) {
+                Err(KvsError::IOError(ref e)) if e.kind() == io::ErrorKind::NotFound && attempts < 10 => {
+                    attempts += 1;
+                },
+                res => break (logs, res?),
             }
+        };
+        for e in loaded.map.iter() {
+            self.map.insert(e.key().clone(), *e.value());
         }
-        offset = new_pow;
+        for e in self.map.iter() {
+            if !loaded.map.contains_key(e.key()) {
+                e.remove();
+            }
+        }
+        // readers of the deleted logs get dropped
+        if let Some(oldest) = logs.first() {
+            self.reader.newest_index.store(*oldest, SeqCst);
+        }
+        Ok(())
+    }
+
+    /// Starts compacting every sealed log on a background thread; writes go
+    /// on into a fresh log meanwhile.
+    fn spawn_compaction(&self, writer: &mut WriteModule) -> Result<()> {
+        let compaction = Compaction {
+            writer: Arc::clone(&self.writer),
+            map: Arc::clone(&self.map),
+            reader: ReadModule::new(Arc::clone(&self.reader.newest_index), Arc::clone(&self.workdir), self.reader.codec),
+            workdir: Arc::clone(&self.workdir),
+            compact_no: writer.start_compaction()?,
+            sync: writer.options.sync != SyncPolicy::Never,
+        };
+        writer.compaction = Some(thread::spawn(move || compaction.run()));
+        Ok(())
     }
+}
+
+impl Drop for KvStore {
+    fn drop(&mut self) {
+        // `pending` is only shared between handles: the last one waits for a
+        // running compaction so the directory is settled once the store is gone
+        if Arc::strong_count(&self.pending) == 1 {
+            let compaction = match self.writer.lock() {
+                Ok(mut writer) => writer.compaction.take(),
+                Err(_) => None,
+            };
+            if let Some(handle) = compaction {
+                let _ = handle.join();
+            }
+            // the background threads may hold the writer a little longer, so
+            // the lock is released here for the directory to reopen right away
+            if let Ok(mut writer) = self.writer.lock() {
+                writer.close();
+            }
+        }
+    }
+}
+
+/// A compaction of every log below `compact_no` into log `compact_no`.
+struct Compaction {
+    writer: Arc<Mutex<WriteModule>>,
+    map: Arc<SkipMap<Vec<u8>, Position>>,
+    reader: ReadModule,
+    workdir: Arc<PathBuf>,
+    compact_no: u64,
+    sync: bool,
+}
+
+impl Compaction {
+    /// Runs the compaction, cleaning up after it if it fails. Failures only
+    /// happen before the index moves to the compacted log.
+    fn run(self) -> Result<()> {
+        let res = self.compact();
+        if let Err(ref e) = res {
+            error!("compaction failed: {}", e);
+            let _ = fs::remove_file(self.workdir.join(get_log_name(self.compact_no)));
+            let _ = fs::remove_file(self.workdir.join(get_hint_name(self.compact_no)));
+            let mut writer = self.writer.lock().unwrap();
+            writer.compacting = false;
+            writer.retry_compaction_at = Some(Instant::now() + COMPACTION_RETRY_DELAY);
+        }
+        res
+    }
+
+    /// Copies the live entries of the sealed logs into the compacted log,
+    /// then swaps them into the index and deletes the old logs.
+    fn compact(&self) -> Result<()> {
+        let path = self.workdir.join(get_log_name(self.compact_no));
+        let mut out = WriteSeeker::new(OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&path)?);
+
+        let now = now_millis();
+        let mut moved = vec![];
+        let mut expired = vec![];
+        for e in self.map.iter() {
+            let old = *e.value();
+            if old.log_no >= self.compact_no {
+                continue;
+            }
+            if old.expired(now) {
+                expired.push((e.key().clone(), old));
+                continue;
+            }
+            let offset = out.pos as u64;
+            if old.chain_len > 0 {
+                // appended values are written out whole
+                let value = self.reader.read(&old)?
+                    .ok_or(KvsError::Corruption { log_no: old.log_no, offset: old.offset })?;
+                write_frame(&mut out, self.reader.codec, &Entry::Put {
+                    key: e.key().clone(),
+                    value,
+                    seq: old.seq,
+                    expires_at: old.expires_at,
+                })?;
+            } else {
+                self.reader.read_and(&old, |mut f| {
+                    Ok(io::copy(&mut f, &mut out)?)
+                })?;
+            }
+            moved.push((e.key().clone(), old, Position {
+                log_no: self.compact_no,
+                offset,
+                size: out.pos as u64 - offset,
+                expires_at: old.expires_at,
+                seq: old.seq,
+                chain_len: 0,
+                chain_size: 0,
+            }));
+        }
+
+        out.flush()?;
+        // the old logs are gone for good, so their data has to be on disk first
+        if self.sync {
+            out.sync()?;
+        }
+        let hints = moved.iter().map(|(k, _, new)| Hint {
+            key: k.clone(),
+            offset: new.offset,
+            size: new.size,
+            expires_at: new.expires_at,
+            seq: new.seq,
+        });
+        write_hints(&self.workdir, self.reader.codec, self.compact_no, hints, self.sync)?;
+
+        // writers only touch the index under this lock, so whatever still
+        // points at its old position was not rewritten during the copy
+        let mut writer = self.writer.lock().unwrap();
+        let mut stats = LogStats { size: out.pos as u64, garbage: 0 };
+        for (k, old, new) in moved {
+            match self.map.get(&k) {
+                Some(e) if *e.value() == old => {
+                    self.map.insert(k, new);
+                },
+                // rewritten during the copy, so the copy is already stale
+                _ => stats.garbage += new.size,
+            }
+        }
+        // expired entries were left behind with their logs
+        for (k, old) in expired {
+            if self.map.get(&k).is_some_and(|e| *e.value() == old) {
+                self.map.remove(&k);
+            }
+        }
+        writer.finish_compaction(self.compact_no, stats);
+        Ok(())
+    }
+}
+
+/// Drops expired keys from the index every `interval`, until the last
+/// handle to the store is dropped.
+fn spawn_sweeper(writer: Weak<Mutex<WriteModule>>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        match writer.upgrade() {
+            Some(writer) => writer.lock().unwrap().sweep_expired(),
+            None => break,
+        }
+    });
+}
+
+/// Syncs writes left behind by `SyncPolicy::Interval` once the store goes
+/// quiet, until the last handle to the store is dropped.
+fn spawn_syncer(writer: Weak<Mutex<WriteModule>>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let writer = match writer.upgrade() {
+            Some(writer) => writer,
+            None => break,
+        };
+        let mut writer = writer.lock().unwrap();
+        if writer.unsynced > 0 && writer.last_sync.elapsed() >= interval {
+            if let Err(e) = writer.sync() {
+                error!("background sync failed: {}", e);
+            }
+        }
+    });
+}
+
+impl WriteModule {
+    /// Syncs what the policy asks for, then lets go of the current log and
+    /// the directory lock. Nothing is written afterwards.
+    fn close(&mut self) {
+        if self.writer.is_some() && self.unsynced > 0 && self.options.sync != SyncPolicy::Never {
+            if let Err(e) = self.sync() {
+                error!("sync on close failed: {}", e);
+            }
+        }
+        self.writer = None;
+        self.lock = None;
+    }
+}
+
+impl Drop for WriteModule {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Replays one log into `map` and returns the length of its replayable
+/// prefix, which is shorter than the file if a crash left a torn entry or
+/// an unfinished batch at its end.
+/// `last_seq` is raised to the highest sequence number met on the way.
+fn init_memory_a_file<R: Read + Seek + Sync>(map: &mut SkipMap<Vec<u8>, Position>, stats: &mut BTreeMap<u64, LogStats>, last_seq: &mut u64, codec: LogCodec, log_no: u64, reader: &mut ReadSeeker<R>) -> Result<u64> {
+    reader.seek(SeekFrom::Start(0))?;
+
+    let mut committed = 0;
+    let mut batch: Option<PendingBatch> = None;
+
+    loop {
+        let offset = reader.pos as u64;
+        // a frame cut off by a crash ends the log, everything before it is intact
+        let e: Entry = match read_frame(reader, codec, log_no, offset)? {
+            Some(e) => e,
+            None => break,
+        };
+        let pos = Position {
+            log_no,
+            offset,
+            size: reader.pos as u64 - offset,
+            expires_at: e.expires_at(),
+            seq: e.seq(),
+            chain_len: 0,
+            chain_size: 0,
+        };
+
+        match (e, batch.as_mut()) {
+            (Entry::Batch(n), _) => {
+                batch = Some((n, pos, Vec::with_capacity(n as usize)));
+            },
+            (e, Some((n, _, entries))) => {
+                entries.push((e, pos));
+                if entries.len() as u64 == *n {
+                    let (_, header, entries) = batch.take().unwrap();
+                    stats.entry(log_no).or_default().garbage += header.size;
+                    for (e, pos) in entries {
+                        *last_seq = (*last_seq).max(pos.seq);
+                        replay_entry(map, stats, e, pos);
+                    }
+                }
+            },
+            (e, None) => {
+                *last_seq = (*last_seq).max(pos.seq);
+                replay_entry(map, stats, e, pos);
+            },
+        }
+        if batch.is_none() {
+            committed = reader.pos as u64;
+        }
+    }
+    stats.entry(log_no).or_default().size = committed;
+    Ok(committed)
+}
+
+/// Applies a replayed entry to `map`, counting whatever it overwrites or
+/// removes, and the tombstone itself, as garbage.
+fn replay_entry(map: &mut SkipMap<Vec<u8>, Position>, stats: &mut BTreeMap<u64, LogStats>, e: Entry, mut pos: Position) {
+    let key = match &e {
+        Entry::Set(k, _) | Entry::SetExpiring(k, ..) | Entry::Remove(k) => k,
+        Entry::Put { key, .. } | Entry::Delete { key, .. } => key,
+        Entry::Append { key, prev_offset, prev_size, .. } => {
+            let prev = map.get(key).map(|e| *e.value())
+                .filter(|prev| prev.log_no == pos.log_no && prev.offset == *prev_offset)
+                .unwrap_or_else(|| pos.prev(*prev_offset, *prev_size));
+            // the value keeps what it is appended to
+            pos.chain_len = prev.chain_len + 1;
+            pos.chain_size = prev.total_size();
+            map.insert(key.clone(), pos);
+            return;
+        },
+        Entry::Batch(_) => return,
+        Entry::Sequence(_) => {
+            stats.entry(pos.log_no).or_default().garbage += pos.size;
+            return;
+        },
+    };
+    if let Some(old) = map.get(key).map(|e| *e.value()) {
+        stats.entry(old.log_no).or_default().garbage += old.total_size();
+    }
+    // expired entries are indexed all the same, and swept once the store is open
+    match e {
+        Entry::Set(k1, _) | Entry::SetExpiring(k1, ..) | Entry::Put { key: k1, .. } => {
+            map.insert(k1, pos);
+        },
+        Entry::Remove(k1) | Entry::Delete { key: k1, .. } => {
+            map.remove(&k1);
+            stats.entry(pos.log_no).or_default().garbage += pos.size;
+        },
+        Entry::Batch(_) | Entry::Sequence(_) | Entry::Append { .. } => {},
+    }
+}
+
+/// Indexes log `log_no` from its hint file. Returns `false` if it has no
+/// complete hint file, in which case the log has to be replayed instead.
+fn load_hints(map: &mut SkipMap<Vec<u8>, Position>, stats: &mut BTreeMap<u64, LogStats>, last_seq: &mut u64, workdir: &Path, codec: LogCodec, log_no: u64) -> Result<bool> {
+    let file = match File::open(workdir.join(get_hint_name(log_no))) {
+        Ok(file) => file,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => Err(e)?,
+    };
+    let len = file.metadata()?.len();
+    let mut reader = ReadSeeker::new(file);
+
+    let mut hints = vec![];
+    while (reader.pos as u64) < len {
+        let offset = reader.pos as u64;
+        match read_frame::<_, Hint>(&mut reader, codec, log_no, offset) {
+            Ok(Some(hint)) => hints.push(hint),
+            Ok(None) | Err(KvsError::Corruption { .. }) => {
+                warn!("{} is damaged, replaying {} instead", get_hint_name(log_no), get_log_name(log_no));
+                return Ok(false);
+            },
+            Err(e) => Err(e)?,
+        }
+    }
+
+    for hint in hints {
+        if let Some(old) = map.get(&hint.key).map(|e| *e.value()) {
+            stats.entry(old.log_no).or_default().garbage += old.total_size();
+        }
+        *last_seq = (*last_seq).max(hint.seq);
+        map.insert(hint.key, Position {
+            log_no,
+            offset: hint.offset,
+            size: hint.size,
+            expires_at: hint.expires_at,
+            seq: hint.seq,
+            chain_len: 0,
+            chain_size: 0,
+        });
+    }
+    stats.entry(log_no).or_default().size = fs::metadata(workdir.join(get_log_name(log_no)))?.len();
+    Ok(true)
+}
+
+/// Writes the hint file of compacted log `log_no` under a temporary name
+/// first, so a crash never leaves a partial hint file behind.
+fn write_hints(workdir: &Path, codec: LogCodec, log_no: u64, hints: impl Iterator<Item = Hint>, sync: bool) -> Result<()> {
+    let path = workdir.join(get_hint_name(log_no));
+    let tmp = path.with_extension("hint.tmp");
+    let mut w = WriteSeeker::new(File::create(&tmp)?);
+    for hint in hints {
+        write_frame(&mut w, codec, &hint)?;
+    }
+    if sync {
+        w.sync()?;
+    } else {
+        w.flush()?;
+    }
+    fs::rename(&tmp, &path)?;
     Ok(())
 }
 
+fn write_frame<W: Write, T: Serialize>(w: &mut W, codec: LogCodec, e: &T) -> Result<()> {
+    let payload = codec.encode(e)?;
+    w.write_all(&(payload.len() as u32).to_le_bytes())?;
+    w.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+    w.write_all(&payload)?;
+    Ok(())
+}
+
+/// Reads the frame starting at `offset` of log `log_no`. Returns `None` if
+/// the log ends before the frame does, and `KvsError::Corruption` if the
+/// frame fails its checksum.
+fn read_frame<R: Read, T: DeserializeOwned>(r: &mut R, codec: LogCodec, log_no: u64, offset: u64) -> Result<Option<T>> {
+    let mut header = Vec::with_capacity(FRAME_HEADER_SIZE);
+    r.by_ref().take(FRAME_HEADER_SIZE as u64).read_to_end(&mut header)?;
+    if header.len() < FRAME_HEADER_SIZE {
+        return Ok(None);
+    }
+    let mut len = [0u8; 4];
+    let mut crc = [0u8; 4];
+    len.copy_from_slice(&header[..4]);
+    crc.copy_from_slice(&header[4..]);
+    let len = u32::from_le_bytes(len) as u64;
+    let crc = u32::from_le_bytes(crc);
+
+    let mut payload = Vec::new();
+    r.by_ref().take(len).read_to_end(&mut payload)?;
+    if (payload.len() as u64) < len {
+        // a frame whose length got damaged still holds its whole payload,
+        // where a torn write left only part of one
+        if holds_payload::<T>(&payload, crc, codec) {
+            Err(KvsError::Corruption { log_no, offset })?
+        }
+        return Ok(None);
+    }
+    if crc32fast::hash(&payload) != crc {
+        Err(KvsError::Corruption { log_no, offset })?
+    }
+    match codec.decode(&payload) {
+        Some(e) => Ok(Some(e)),
+        None => Err(KvsError::Corruption { log_no, offset }),
+    }
+}
+
+/// Whether some prefix of `rest` matches the frame checksum `crc` and
+/// decodes, which the part of a payload left by a torn write cannot do.
+fn holds_payload<T: DeserializeOwned>(rest: &[u8], crc: u32, codec: LogCodec) -> bool {
+    let mut hasher = crc32fast::Hasher::new();
+    rest.iter().enumerate().any(|(i, byte)| {
+        hasher.update(&[*byte]);
+        hasher.clone().finalize() == crc && codec.decode::<T>(&rest[..=i]).is_some()
+    })
+}
+
+/// The bytes of `v` from `from` to `to`, both cut down to its length.
+fn slice(mut v: Vec<u8>, from: u64, to: u64) -> Vec<u8> {
+    let len = v.len() as u64;
+    v.truncate(to.min(len) as usize);
+    v.drain(..from.min(len) as usize);
+    v
+}
+
 fn get_log_numbers(file_path: PathBuf) -> Result<Vec<u64>> {     
     let mut logs: Vec<u64> = fs::read_dir(file_path)?
         .flat_map(|f| -> Result<_> {Ok(f?.path())})
@@ -350,6 +1635,96 @@ fn get_log_name(id: u64) -> String {
     format!("{}.log", id)
 }
 
+fn get_hint_name(id: u64) -> String {
+    format!("{}.hint", id)
+}
+
+/// Fails if `workdir` belongs to another engine, or to a kvs format this
+/// version cannot read. Openers check again once they hold the lock.
+fn check_engine(workdir: &Path) -> Result<()> {
+    match Manifest::load(workdir)? {
+        Some(manifest) => manifest.check(ENGINE_NAME, FORMAT_VERSION),
+        None => manifest::check_unmanifested(workdir, ENGINE_NAME),
+    }
+}
+
+/// Takes the advisory lock keeping other writers out of `workdir`, which
+/// lasts as long as the returned file stays open.
+fn lock_dir(workdir: &Path) -> Result<File> {
+    let file = OpenOptions::new()
+                .write(true)
+                .truncate(false)
+                .create(true)
+                .open(workdir.join(LOCK_NAME))?;
+    match file.try_lock_exclusive() {
+        Ok(()) => Ok(file),
+        Err(ref e) if e.kind() == fs2::lock_contended_error().kind() => Err(KvsError::Locked),
+        Err(e) => Err(e)?,
+    }
+}
+
+/// Moves the manifest of a store opened for writing to the current format
+/// version, so that builds which cannot read what it may now contain stay away.
+fn bump_manifest(workdir: &Path, mut manifest: Manifest) -> Result<()> {
+    if manifest.format_version < FORMAT_VERSION {
+        manifest.format_version = FORMAT_VERSION;
+        manifest.save(workdir)?;
+    }
+    Ok(())
+}
+
+fn save_manifest(workdir: &Path, codec: LogCodec) -> Result<()> {
+    let mut manifest = Manifest::new(ENGINE_NAME, FORMAT_VERSION);
+    manifest.codec = Some(codec);
+    manifest.save(workdir)
+}
+
+/// Tells whether log `log_no` is in the version 0 format, which starts
+/// right away with a JSON entry instead of a frame header.
+fn is_legacy_log(workdir: &Path, log_no: u64) -> Result<bool> {
+    let reader = BufReader::new(File::open(workdir.join(get_log_name(log_no)))?);
+    let first = serde_json::Deserializer::from_reader(reader)
+        .into_iter::<LegacyEntry>()
+        .next();
+    Ok(matches!(first, Some(Ok(_))))
+}
+
+/// Tells which codec the framed logs of a store without a manifest were
+/// written with, going by the first entry that decodes. `None` if no log
+/// starts with one.
+fn detect_codec(workdir: &Path, logs: &[u64]) -> Result<Option<LogCodec>> {
+    for log_no in logs.iter() {
+        for codec in &[LogCodec::Json, LogCodec::Bincode] {
+            let mut reader = BufReader::new(File::open(workdir.join(get_log_name(*log_no)))?);
+            if let Ok(Some(_)) = read_frame::<_, Entry>(&mut reader, *codec, *log_no, 0) {
+                return Ok(Some(*codec));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// Rewrites version 0 log `log_no` as frames, through a temporary file.
+fn upgrade_log(workdir: &Path, codec: LogCodec, log_no: u64) -> Result<()> {
+    let path = workdir.join(get_log_name(log_no));
+    let tmp = path.with_extension("log.upgrade");
+    let reader = BufReader::new(File::open(&path)?);
+    let mut w = WriteSeeker::new(File::create(&tmp)?);
+
+    for e in serde_json::Deserializer::from_reader(reader).into_iter::<LegacyEntry>() {
+        let e = match e {
+            Ok(e) => e,
+            // the old format could be left with a torn entry at its end
+            Err(ref e) if e.is_eof() => break,
+            Err(e) => Err(e)?,
+        };
+        write_frame(&mut w, codec, &Entry::from(e))?;
+    }
+    w.sync()?;
+    fs::rename(&tmp, &path)?;
+    Ok(())
+}
+
 struct ReadSeeker <R: Read + Seek> {
     reader: BufReader<R>,
     pos: usize,
@@ -405,6 +1780,13 @@ impl<R: Write + Seek + Sync> Write for WriteSeeker<R> {
     }
 }
 
+impl WriteSeeker<File> {
+    fn sync(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()
+    }
+}
+
 impl<W: Write + Seek + Sync> Seek for WriteSeeker<W> {
     fn seek(&mut self, pos: SeekFrom) -> std::result::Result<u64, std::io::Error> {
         self.pos = self.writer.seek(pos)? as usize;
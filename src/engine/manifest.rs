@@ -0,0 +1,119 @@
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Deserialize};
+
+use crate::engine::LogCodec;
+use crate::err::*;
+
+const MANIFEST_NAME: &str = "MANIFEST";
+
+/// Written by `kvs-server` before data directories had a manifest.
+const ENGINE_FLAG_NAME: &str = ".engine_flag";
+
+/// Describes the store a data directory holds, kept in its `MANIFEST` file.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Manifest {
+    pub engine: String,
+    /// Version of the engine's on-disk format.
+    pub format_version: u32,
+    /// Encoding of the logs, for `KvStore` only.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub codec: Option<LogCodec>,
+    /// Seconds since the Unix epoch.
+    pub created_at: u64,
+    pub store_id: String,
+}
+
+impl Manifest {
+    /// Describes a new store of `engine`.
+    pub fn new(engine: &str, format_version: u32) -> Self {
+        Self {
+            engine: engine.to_owned(),
+            format_version,
+            codec: None,
+            created_at: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            store_id: format!("{:016x}{:016x}", rand::random::<u64>(), rand::random::<u64>()),
+        }
+    }
+
+    /// Reads the manifest of `dir`, if it has one.
+    pub fn load(dir: &Path) -> Result<Option<Self>> {
+        match fs::read(dir.join(MANIFEST_NAME)) {
+            Ok(content) => Ok(Some(serde_json::from_slice(&content)?)),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e)?,
+        }
+    }
+
+    /// Writes the manifest of `dir` under a temporary name first, so a crash
+    /// never leaves a directory with a partial manifest.
+    pub fn save(&self, dir: &Path) -> Result<()> {
+        let path = dir.join(MANIFEST_NAME);
+        let tmp = path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec_pretty(self)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &path)?;
+
+        // the manifest supersedes it
+        match fs::remove_file(dir.join(ENGINE_FLAG_NAME)) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+            res => Ok(res?),
+        }
+    }
+
+    /// Checks that the store was written by `engine`, in `format_version`
+    /// or an older format the engine still reads.
+    pub fn check(&self, engine: &str, format_version: u32) -> Result<()> {
+        if self.engine != engine {
+            Err(KvsError::WrongEngine { expected: engine.to_owned(), found: self.engine.clone() })?
+        }
+        if self.format_version > format_version {
+            Err(KvsError::UnsupportedFormat(self.format_version))?
+        }
+        Ok(())
+    }
+}
+
+/// Checks that a directory without a manifest was not written by an engine
+/// other than `engine`, going by the `.engine_flag` of older servers or by
+/// the files it holds.
+pub(crate) fn check_unmanifested(dir: &Path, engine: &str) -> Result<()> {
+    let found = match fs::read_to_string(dir.join(ENGINE_FLAG_NAME)) {
+        Ok(flag) => Some(flag),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => detect_engine(dir)?,
+        Err(e) => Err(e)?,
+    };
+    match found {
+        Some(found) if found != engine => {
+            Err(KvsError::WrongEngine { expected: engine.to_owned(), found })
+        },
+        _ => Ok(()),
+    }
+}
+
+fn detect_engine(dir: &Path) -> Result<Option<String>> {
+    if !dir.exists() {
+        return Ok(None);
+    }
+    for f in fs::read_dir(dir)? {
+        let path = f?.path();
+        // kvs names its logs `N.log`, other `.log` files are no sign of it
+        let numbered = path.file_stem()
+            .and_then(|s| s.to_str())
+            .is_some_and(|s| s.parse::<u64>().is_ok());
+        if path.is_file() && path.extension() == Some("log".as_ref()) && numbered {
+            return Ok(Some("kvs".to_owned()));
+        }
+    }
+    if dir.join("conf").exists() && dir.join("db").exists() {
+        return Ok(Some("sled".to_owned()));
+    }
+    Ok(None)
+}
@@ -1,15 +1,135 @@
+use std::ops::{Bound, RangeBounds};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use crate::err::*;
 
+mod batch;
 mod kv;
+mod manifest;
+mod options;
 mod sled;
 
-pub use self::kv::KvStore;
+pub use self::batch::{BatchOp, WriteBatch};
+pub use self::kv::{KvStore, LogCodec};
+pub use self::manifest::Manifest;
+pub use self::options::{KvStoreOptions, SyncPolicy};
 pub use self::sled::SledKvsEngine;
 
+/// A key and its value, as returned by range scans.
+pub type KvPair = (Vec<u8>, Vec<u8>);
+
+/// A value and its version, the sequence number of the write that set it.
+/// Sequence numbers only grow, and values written before stores kept them
+/// have version 0.
+pub type Versioned = (Vec<u8>, u64);
+
 pub trait KvsEngine: Clone + Send + 'static {
-    fn set(&self, key: String, value: String) -> Result<()>;
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Sets `key` to `value` until `ttl` has passed, after which it reads
+    /// as missing.
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// Makes the current value of `key` expire once `ttl` has passed.
+    /// Fails with `KvsError::NoEntryError` if there is none.
+    fn expire(&self, key: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Returns `len` bytes of the value of `key` starting at `offset`, or
+    /// as many as it has.
+    fn get_range(&self, key: Vec<u8>, offset: u64, len: u64) -> Result<Option<Vec<u8>>>;
+
+    /// Returns the value of `key` along with its version.
+    fn get_versioned(&self, key: Vec<u8>) -> Result<Option<Versioned>>;
+
+    /// Atomically replaces the value of `key` with `new` if it currently is
+    /// `expected`, where `None` stands for a missing key on either side.
+    /// Fails with `KvsError::Conflict` if the value was anything else.
+    fn compare_and_swap(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<()>;
+
+    /// Like `compare_and_swap`, but checks the version of the current value,
+    /// `None` standing for a missing key. Returns the version of `new`.
+    fn compare_version_and_swap(&self, key: Vec<u8>, version: Option<u64>, new: Option<Vec<u8>>) -> Result<Option<u64>>;
+
+    /// Appends `suffix` to the value of `key`, a missing key counting as
+    /// empty, and returns the length of the result.
+    fn append(&self, key: Vec<u8>, suffix: Vec<u8>) -> Result<u64>;
+
+    /// Adds `delta` to the integer stored as decimal text under `key`, a
+    /// missing key counting as 0, and returns the result. Fails with
+    /// `KvsError::NotANumber` if the value is anything else.
+    fn incr(&self, key: Vec<u8>, delta: i64) -> Result<i64>;
+
+    /// Subtracts `delta` from the integer under `key`, see `incr`.
+    fn decr(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        self.incr(key, delta.checked_neg().ok_or(KvsError::Overflow)?)
+    }
+
+    /// Sets `key` to `value` unless it already has one, returning whether
+    /// it was set.
+    fn set_if_absent(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        match self.compare_and_swap(key, None, Some(value)) {
+            Ok(()) => Ok(true),
+            Err(KvsError::Conflict) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn remove(&self, key: Vec<u8>) -> Result<()>;
+
+    /// Applies every operation of `batch` atomically.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Returns the pairs whose keys fall in `range`, in ascending key order,
+    /// stopping after `limit` pairs if one is given.
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<Vec<KvPair>>;
+
+    /// Reclaims the space of overwritten and removed entries now, returning
+    /// once that is done.
+    fn compact(&self) -> Result<()>;
+
+    /// Makes every write done so far durable, whatever the store would
+    /// otherwise sync. Servers call it before shutting down.
+    fn flush(&self) -> Result<()>;
+
+    /// Returns the pairs whose keys start with `prefix`, in ascending key order.
+    fn prefix(&self, prefix: Vec<u8>, limit: Option<usize>) -> Result<Vec<KvPair>> {
+        self.scan(prefix_range(prefix), limit)
+    }
+}
+
+/// Milliseconds since the Unix epoch, which expiry times are counted in.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
+
+pub(crate) fn expiry_after(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
 
-    fn get(&self, key: String) -> Result<Option<String>>;
+/// Adds `delta` to the counter `value`, a missing value counting as 0.
+pub(crate) fn add_to_counter(value: Option<&[u8]>, delta: i64) -> Result<i64> {
+    let n = match value {
+        Some(v) => std::str::from_utf8(v).ok()
+            .and_then(|s| s.parse::<i64>().ok())
+            .ok_or(KvsError::NotANumber)?,
+        None => 0,
+    };
+    n.checked_add(delta).ok_or(KvsError::Overflow)
+}
 
-    fn remove(&self, key: String) -> Result<()>;
-}
\ No newline at end of file
+/// Turns a key prefix into the range of keys starting with it.
+pub fn prefix_range(prefix: Vec<u8>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    let mut end = prefix.clone();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (Bound::Included(prefix), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix), Bound::Unbounded)
+}
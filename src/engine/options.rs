@@ -0,0 +1,80 @@
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::engine::LogCodec;
+use crate::err::*;
+
+/// When `KvStore` forces written entries to disk with `fsync`.
+///
+/// Entries are always flushed to the OS before a write returns, so the
+/// policy only matters when the machine, not just the process, goes down.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPolicy {
+    /// Never sync, leave it to the OS.
+    Never,
+    /// Sync before every write returns.
+    EveryWrite,
+    /// Sync once every `n` writes.
+    EveryN(u64),
+    /// Sync at most once per interval, and in the background after it.
+    Interval(Duration),
+}
+
+impl FromStr for SyncPolicy {
+    type Err = KvsError;
+
+    /// Parses `never`, `always`, `every:<writes>` or `interval:<millis>`.
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || KvsError::StringError(format!("invalid sync policy {}", s));
+        let (kind, arg) = match s.find(':') {
+            Some(i) => (&s[..i], Some(&s[i + 1..])),
+            None => (s, None),
+        };
+        let arg = || arg.and_then(|a| a.parse::<u64>().ok())
+                .filter(|a| *a > 0)
+                .ok_or_else(invalid);
+
+        match kind {
+            "never" => Ok(SyncPolicy::Never),
+            "always" => Ok(SyncPolicy::EveryWrite),
+            "every" => Ok(SyncPolicy::EveryN(arg()?)),
+            "interval" => Ok(SyncPolicy::Interval(Duration::from_millis(arg()?))),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// Tuning knobs of `KvStore::open_with_options`.
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub sync: SyncPolicy,
+    /// Compacts once this many bytes of the logs are overwritten or removed
+    /// entries.
+    pub compaction_threshold: u64,
+    /// Also compacts once such garbage makes up this fraction of the logs.
+    pub compaction_ratio: Option<f64>,
+    /// Encoding of the entries of a newly created store.
+    pub codec: LogCodec,
+    /// Opens the store without locking it or writing anything to it, so it
+    /// can sit next to a writer. It sees the writes made before it was
+    /// opened, and reloads its index once the writer compacts away a log it
+    /// reads, catching up with the writes made since. Its own writes fail
+    /// with `KvsError::ReadOnly`.
+    pub read_only: bool,
+    /// How often keys past their expiry are dropped from the index. They
+    /// read as missing either way.
+    pub sweep_interval: Duration,
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self {
+            sync: SyncPolicy::Never,
+            compaction_threshold: 1024 * 1024,
+            compaction_ratio: None,
+            codec: LogCodec::Json,
+            read_only: false,
+            sweep_interval: Duration::from_secs(1),
+        }
+    }
+}
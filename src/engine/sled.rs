@@ -1,50 +1,348 @@
+use std::fs;
+use std::convert::TryInto;
+use std::ops::RangeBounds;
 use std::path::PathBuf;
+use std::sync::{Arc, Weak};
+use std::thread;
+use std::time::Duration;
 
 use crate::{err::*, KvsEngine};
+use crate::engine::{KvPair, BatchOp, Versioned, WriteBatch, add_to_counter, expiry_after, now_millis};
+use crate::engine::manifest::{self, Manifest};
 
-use sled::Db;
+use log::error;
+use sled::transaction::{self, ConflictableTransactionResult, TransactionError, TransactionalTree};
+use sled::{Db, IVec, Transactional, Tree};
+
+const ENGINE_NAME: &str = "sled";
+
+/// Only tells stores of this engine apart from others, sled keeps track of
+/// its own format.
+const FORMAT_VERSION: u32 = 1;
+
+/// Side tree mapping keys that expire to their expiry time, in milliseconds
+/// since the Unix epoch as big-endian bytes.
+const EXPIRY_TREE: &str = "expiry";
+
+/// Side tree mapping keys to the version of their value, as big-endian
+/// bytes. Versions come from sled's own monotonic id generator.
+const VERSION_TREE: &str = "version";
+
+type TxResult<T> = ConflictableTransactionResult<T, KvsError>;
+
+/// How often expired keys are dropped, besides when they are read.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(1);
 
 #[derive(Clone)]
 pub struct SledKvsEngine {
-    db: Db, 
+    trees: Arc<Trees>,
+}
+
+struct Trees {
+    db: Db,
+    expiry: Tree,
+    version: Tree,
 }
 
 impl KvsEngine for SledKvsEngine {
-    fn set(&self, key: String, value: String) -> Result<()> {
-        self.db.insert(key, value.as_bytes())?;
-        self.db.flush()?;
-        Ok(())
+    fn set(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.transaction(|tx| {
+            tx.put(&key, &value, None)?;
+            Ok(())
+        })
     }
 
-    fn get(&self, key: String) -> Result<Option<String>> {
-        let res = self.db.get(key)?
-                .map(|iv|iv.to_vec())
-                .map(|v|  {
-                    String::from_utf8(v)
-                })
-                .transpose()?;
+    fn set_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let at = expiry_after(ttl);
+        self.transaction(|tx| {
+            tx.put(&key, &value, Some(at))?;
+            Ok(())
+        })
+    }
+
+    fn expire(&self, key: Vec<u8>, ttl: Duration) -> Result<()> {
+        let at = expiry_after(ttl);
+        self.transaction(|tx| {
+            match tx.get(&key)? {
+                Some((value, _)) => tx.put(&key, &value, Some(at))?,
+                None => return transaction::abort(KvsError::NoEntryError),
+            };
+            Ok(())
+        })
+    }
+
+    fn get(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let res = self.trees.db.get(&key)?
+                .map(|iv|iv.to_vec());
+        if res.is_some() && expired(self.trees.expiry.get(&key)?) {
+            self.purge(&key)?;
+            return Ok(None);
+        }
         Ok(res)
     }
 
-    fn remove(&self, key: String) -> Result<()> {
-        self.db.remove(key)?
-                .ok_or(KvsError::NoEntryError)?;
-        self.db.flush()?;
+    fn get_range(&self, key: Vec<u8>, offset: u64, len: u64) -> Result<Option<Vec<u8>>> {
+        Ok(self.get(key)?.map(|value| {
+            let start = offset.min(value.len() as u64) as usize;
+            let end = offset.saturating_add(len).min(value.len() as u64) as usize;
+            value[start..end].to_vec()
+        }))
+    }
+
+    fn get_versioned(&self, key: Vec<u8>) -> Result<Option<Versioned>> {
+        let res = self.transaction(|tx| tx.get(&key))?;
+        if res.is_none() && self.trees.db.contains_key(&key)? {
+            self.purge(&key)?;
+        }
+        Ok(res.map(|(value, version)| (value.to_vec(), version)))
+    }
+
+    /// Expired keys count as missing. A swap that succeeds leaves the key
+    /// without an expiry time, like a plain set.
+    fn compare_and_swap(&self, key: Vec<u8>, expected: Option<Vec<u8>>, new: Option<Vec<u8>>) -> Result<()> {
+        self.transaction(|tx| {
+            let current = tx.get(&key)?;
+            if current.as_ref().map(|(value, _)| value.as_ref()) != expected.as_deref() {
+                return transaction::abort(KvsError::Conflict);
+            }
+            tx.swap(&key, new.as_deref())?;
+            Ok(())
+        })
+    }
+
+    fn compare_version_and_swap(&self, key: Vec<u8>, version: Option<u64>, new: Option<Vec<u8>>) -> Result<Option<u64>> {
+        self.transaction(|tx| {
+            if tx.get(&key)?.map(|(_, version)| version) != version {
+                return transaction::abort(KvsError::Conflict);
+            }
+            tx.swap(&key, new.as_deref())
+        })
+    }
+
+    /// Runs in a transaction rather than `Tree::update_and_fetch`, as the
+    /// counter gets a new version along with its value.
+    fn incr(&self, key: Vec<u8>, delta: i64) -> Result<i64> {
+        self.transaction(|tx| {
+            let current = tx.get(&key)?;
+            let expires_at = match current {
+                Some(_) => decode_u64(tx.expiry.get(key.as_slice())?),
+                None => None,
+            };
+            let n = match add_to_counter(current.as_ref().map(|(value, _)| value.as_ref()), delta) {
+                Ok(n) => n,
+                Err(e) => return transaction::abort(e),
+            };
+            tx.put(&key, n.to_string().as_bytes(), expires_at)?;
+            Ok(n)
+        })
+    }
+
+    fn append(&self, key: Vec<u8>, suffix: Vec<u8>) -> Result<u64> {
+        self.transaction(|tx| {
+            let (mut value, expires_at) = match tx.get(&key)? {
+                Some((value, _)) => (value.to_vec(), decode_u64(tx.expiry.get(key.as_slice())?)),
+                None => (vec![], None),
+            };
+            value.extend_from_slice(&suffix);
+            tx.put(&key, &value, expires_at)?;
+            Ok(value.len() as u64)
+        })
+    }
+
+    fn remove(&self, key: Vec<u8>) -> Result<()> {
+        self.transaction(|tx| {
+            if !tx.delete(&key)? {
+                return transaction::abort(KvsError::NoEntryError);
+            }
+            Ok(())
+        })
+    }
+
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let ops = batch.into_ops();
+        self.transaction(|tx| {
+            for op in &ops {
+                match op {
+                    BatchOp::Set(k, v) => {
+                        tx.put(k, v, None)?;
+                    },
+                    BatchOp::Remove(k) => {
+                        tx.delete(k)?;
+                    },
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R, limit: Option<usize>) -> Result<Vec<KvPair>> {
+        let mut pairs = Vec::new();
+        for kv in self.trees.db.range(range) {
+            if pairs.len() >= limit.unwrap_or(usize::MAX) {
+                break;
+            }
+            let (k, v) = kv?;
+            if expired(self.trees.expiry.get(&k)?) {
+                self.purge(&k)?;
+                continue;
+            }
+            pairs.push((k.to_vec(), v.to_vec()));
+        }
+        Ok(pairs)
+    }
+
+    /// sled reclaims space on its own, so this only drops expired keys and
+    /// flushes it.
+    fn compact(&self) -> Result<()> {
+        self.sweep_expired()?;
+        self.trees.db.flush()?;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.trees.db.flush()?;
         Ok(())
     }
 }
 
 impl SledKvsEngine {
+    /// Wraps an opened database as is, without looking at its manifest.
     pub fn new(db: Db) -> Result<Self> {
-        Ok(Self {
-            db
+        let expiry = db.open_tree(EXPIRY_TREE)?;
+        let version = db.open_tree(VERSION_TREE)?;
+        let engine = Self {
+            trees: Arc::new(Trees {
+                db,
+                expiry,
+                version,
+            }),
+        };
+        spawn_sweeper(Arc::downgrade(&engine.trees), SWEEP_INTERVAL);
+        Ok(engine)
+    }
+
+    /// Runs `f` atomically over the data and its side trees, then flushes.
+    fn transaction<F, T>(&self, f: F) -> Result<T>
+    where
+        F: Fn(&Txn) -> TxResult<T>,
+    {
+        let res = (&*self.trees.db, &self.trees.expiry, &self.trees.version)
+            .transaction(|(db, expiry, version)| f(&Txn { db, expiry, version }))
+            .map_err(|e| match e {
+                TransactionError::Abort(e) => e,
+                TransactionError::Storage(e) => e.into(),
+            })?;
+        self.trees.db.flush()?;
+        Ok(res)
+    }
+
+    /// Drops every key that has expired by now.
+    fn sweep_expired(&self) -> Result<()> {
+        for kv in self.trees.expiry.iter() {
+            let (k, at) = kv?;
+            if expired(Some(at)) {
+                self.purge(&k)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Removes `key` if it is still expired, racing writers that renew it.
+    fn purge(&self, key: &[u8]) -> Result<()> {
+        self.transaction(|tx| {
+            if expired(tx.expiry.get(key)?) {
+                tx.delete(key)?;
+            }
+            Ok(())
         })
     }
 
+    /// Opens the store at `path`, creating it if needed, after checking
+    /// that the directory does not belong to another engine.
     pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
-        let db = sled::open(path.into())?;
-        Ok(Self {
-            db,
-        })
+        let path = path.into();
+        match Manifest::load(&path)? {
+            Some(manifest) => manifest.check(ENGINE_NAME, FORMAT_VERSION)?,
+            None => {
+                manifest::check_unmanifested(&path, ENGINE_NAME)?;
+                fs::create_dir_all(&path)?;
+                Manifest::new(ENGINE_NAME, FORMAT_VERSION).save(&path)?;
+            },
+        }
+        Self::new(sled::open(path)?)
     }
-}
\ No newline at end of file
+}
+
+/// The trees of a store, inside a transaction.
+struct Txn<'a> {
+    db: &'a TransactionalTree,
+    expiry: &'a TransactionalTree,
+    version: &'a TransactionalTree,
+}
+
+impl Txn<'_> {
+    /// The live value of `key` and its version.
+    fn get(&self, key: &[u8]) -> TxResult<Option<(IVec, u64)>> {
+        if expired(self.expiry.get(key)?) {
+            return Ok(None);
+        }
+        match self.db.get(key)? {
+            Some(value) => Ok(Some((value, decode_u64(self.version.get(key)?).unwrap_or(0)))),
+            None => Ok(None),
+        }
+    }
+
+    /// Writes `value` under a new version and returns it.
+    fn put(&self, key: &[u8], value: &[u8], expires_at: Option<u64>) -> TxResult<u64> {
+        let version = self.db.generate_id()?;
+        self.db.insert(key, value)?;
+        self.version.insert(key, &version.to_be_bytes())?;
+        match expires_at {
+            Some(at) => self.expiry.insert(key, &at.to_be_bytes())?,
+            None => self.expiry.remove(key)?,
+        };
+        Ok(version)
+    }
+
+    /// Removes `key`, returning whether it had a live value.
+    fn delete(&self, key: &[u8]) -> TxResult<bool> {
+        let live = !expired(self.expiry.remove(key)?);
+        self.version.remove(key)?;
+        Ok(self.db.remove(key)?.is_some() && live)
+    }
+
+    /// Sets `key` to `new`, or removes it if there is none, returning the
+    /// new version.
+    fn swap(&self, key: &[u8], new: Option<&[u8]>) -> TxResult<Option<u64>> {
+        match new {
+            Some(value) => Ok(Some(self.put(key, value, None)?)),
+            None => {
+                self.delete(key)?;
+                Ok(None)
+            },
+        }
+    }
+}
+
+/// Drops expired keys every `interval`, so that keys nobody reads again
+/// do not linger, until the last handle to the engine is dropped.
+fn spawn_sweeper(trees: Weak<Trees>, interval: Duration) {
+    thread::spawn(move || loop {
+        thread::sleep(interval);
+        let engine = match trees.upgrade() {
+            Some(trees) => SledKvsEngine { trees },
+            None => break,
+        };
+        if let Err(e) = engine.sweep_expired() {
+            error!("sweeping expired keys failed: {}", e);
+        }
+    });
+}
+
+fn decode_u64(bytes: Option<IVec>) -> Option<u64> {
+    bytes.and_then(|b| b.as_ref().try_into().ok()).map(u64::from_be_bytes)
+}
+
+fn expired(expires_at: Option<IVec>) -> bool {
+    decode_u64(expires_at).is_some_and(|at| at <= now_millis())
+}
@@ -11,6 +11,8 @@ pub enum KvsError {
     IOError(io::Error),
     #[fail(display = "Serialize error, {}", _0)]
     SerializeError(serde_json::Error),
+    #[fail(display = "Bincode error, {}", _0)]
+    BincodeError(bincode::Error),
     #[fail(display = "Operation error")]
     OperationError,
     #[fail(display = "Sled error, {}", _0)]
@@ -18,20 +20,43 @@ pub enum KvsError {
 
     #[fail(display = "Key not found")]
     NoEntryError,
+    #[fail(display = "Value does not match the expected one")]
+    Conflict,
+    #[fail(display = "Value is not an integer")]
+    NotANumber,
+    #[fail(display = "Counter would overflow")]
+    Overflow,
     #[fail(display = "Log type wrong")]
     LogError,
+    #[fail(display = "Corrupted record in log {} at offset {}", log_no, offset)]
+    Corruption { log_no: u64, offset: u64 },
+    #[fail(display = "Store format version {} is not supported by this version of kvs", _0)]
+    UnsupportedFormat(u32),
+    #[fail(display = "Store format version {} is outdated, upgrade the store first", _0)]
+    OutdatedFormat(u32),
+    #[fail(display = "Data directory is locked by another store")]
+    Locked,
+    #[fail(display = "Store is read-only")]
+    ReadOnly,
     #[fail(display = "Subcommand type wrong")]
     SubCmdError,
     #[fail(display = "Utf8 encode/decode error: {}", _0)]
     Utf8Error(FromUtf8Error),
     #[fail(display = "Engine error")]
     EngineError,
+    #[fail(display = "Data directory belongs to the {} engine, not {}", found, expected)]
+    WrongEngine { expected: String, found: String },
     #[fail(display = "Rayon error, {}", _0)]
     RayonError(ThreadPoolBuildError),
 
     #[fail(display = "Addr Parse Error, {}", _0)]
     AddrParseError(AddrParseError),
 
+    #[fail(display = "Invalid request, {}", _0)]
+    InvalidRequest(String),
+    #[fail(display = "Server error, {}", _0)]
+    ServerError(String),
+
     #[fail(display = "{}", _0)]
     StringError(String),
 }
@@ -42,6 +67,12 @@ impl From<serde_json::Error> for KvsError {
     }
 }
 
+impl From<bincode::Error> for KvsError {
+    fn from (e: bincode::Error) -> KvsError {
+        KvsError::BincodeError(e)
+    }
+}
+
 impl From<io::Error> for KvsError {
     fn from (e: io::Error) -> KvsError {
         KvsError::IOError(e)
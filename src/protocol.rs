@@ -1,50 +1,196 @@
-use std::io::{Write, Read};
+use std::io::{self, Write, Read};
+use std::ops::Bound;
+use std::time::Duration;
 
+use crate::engine::{KvPair, Versioned, WriteBatch};
 use crate::err::*;
 
 use serde::{Serialize, Deserialize};
+use serde::de::DeserializeOwned;
 use tokio::io::{ AsyncWrite, AsyncWriteExt};
 use tokio::io::{ AsyncRead, AsyncReadExt};
 
 #[derive(Serialize, Deserialize)]
 pub enum Request {
-    Set(String, String),
-    Get(String),
-    Remove(String),
+    Set(Vec<u8>, Vec<u8>),
+    SetWithTtl(Vec<u8>, Vec<u8>, Duration),
+    Expire(Vec<u8>, Duration),
+    Get(Vec<u8>),
+    GetVersioned(Vec<u8>),
+    /// Gets `len` bytes of a value from an offset on.
+    GetRange(Vec<u8>, u64, u64),
+    Remove(Vec<u8>),
+    CompareAndSwap(Vec<u8>, Option<Vec<u8>>, Option<Vec<u8>>),
+    CompareVersionAndSwap(Vec<u8>, Option<u64>, Option<Vec<u8>>),
+    /// Adds to a counter, a negative delta subtracting from it.
+    Incr(Vec<u8>, i64),
+    Append(Vec<u8>, Vec<u8>),
+    Scan(Bound<Vec<u8>>, Bound<Vec<u8>>, Option<usize>),
+    Batch(WriteBatch),
+    Compact,
 }
 
 #[derive(Serialize, Deserialize)]
 pub enum Response {
-    Get(Option<String>), 
+    Get(Option<Vec<u8>>),
+    GetVersioned(Option<Versioned>),
+    /// The version a conditional write gave its key, `None` if it removed it.
+    Version(Option<u64>),
+    Counter(i64),
+    /// The length of a value after an append.
+    Length(u64),
+    Scan(Vec<KvPair>),
     Ok,
-    Error(String),
+    /// The request failed, with what kind of error and its message.
+    Error(ErrorCode, String),
 }
 
+/// The kinds of error a request can fail with, which `KvsClient` turns
+/// back into the matching `KvsError`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    /// `KvsError::NoEntryError`.
+    NotFound,
+    /// `KvsError::Conflict`.
+    Conflict,
+    /// `KvsError::NotANumber`.
+    NotANumber,
+    /// `KvsError::Overflow`.
+    Overflow,
+    /// `KvsError::ReadOnly`.
+    ReadOnly,
+    /// The request cannot be carried out as given.
+    Invalid,
+    /// Anything going wrong on the server's side.
+    Internal,
+}
+
+impl ErrorCode {
+    pub fn of(e: &KvsError) -> Self {
+        match e {
+            KvsError::NoEntryError => ErrorCode::NotFound,
+            KvsError::Conflict => ErrorCode::Conflict,
+            KvsError::NotANumber => ErrorCode::NotANumber,
+            KvsError::Overflow => ErrorCode::Overflow,
+            KvsError::ReadOnly => ErrorCode::ReadOnly,
+            KvsError::OperationError
+            | KvsError::SubCmdError
+            | KvsError::Utf8Error(_)
+            | KvsError::AddrParseError(_)
+            | KvsError::InvalidRequest(_) => ErrorCode::Invalid,
+            _ => ErrorCode::Internal,
+        }
+    }
+
+    /// The error a client reports for a response with this code.
+    pub fn into_error(self, message: String) -> KvsError {
+        match self {
+            ErrorCode::NotFound => KvsError::NoEntryError,
+            ErrorCode::Conflict => KvsError::Conflict,
+            ErrorCode::NotANumber => KvsError::NotANumber,
+            ErrorCode::Overflow => KvsError::Overflow,
+            ErrorCode::ReadOnly => KvsError::ReadOnly,
+            ErrorCode::Invalid => KvsError::InvalidRequest(message),
+            ErrorCode::Internal => KvsError::ServerError(message),
+        }
+    }
+}
+
+/// Requests and responses are framed as `len: u32 | payload` (big-endian),
+/// the payload being JSON, so a connection can carry any number of them.
+const FRAME_HEADER_SIZE: usize = 4;
+
+/// A request or response tagged with the id the client gave the request,
+/// which its response carries back. Clients may send further requests
+/// before the responses to earlier ones have arrived.
+#[derive(Serialize, Deserialize)]
+struct Envelope<T> {
+    id: u64,
+    body: T,
+}
+
+/// Frames longer than this are refused rather than buffered, as they most
+/// likely come from something that does not speak the protocol.
+const MAX_FRAME_SIZE: u32 = 64 << 20;
+
 impl Request {
-    pub async fn write(&self, mut w: impl AsyncWrite + Unpin + Send) -> Result<()> {
-        let data = serde_json::to_string(self)?;
-        w.write(data.as_bytes()).await?;
+    pub async fn write(&self, id: u64, mut w: impl AsyncWrite + Unpin + Send) -> Result<()> {
+        write_frame_async(&mut w, &Envelope { id, body: self }).await?;
         w.flush().await?;
         Ok(())
     }
 
-    pub fn read_from(r: impl Read) -> Result<Self> {
-        let mut de = serde_json::Deserializer::from_reader(r);
-        Ok(Request::deserialize(&mut de)?)
+    /// Reads the next request and its id, or `None` if the peer closed the
+    /// connection instead of sending one.
+    pub fn read_from(r: impl Read) -> Result<Option<(u64, Self)>> {
+        Ok(read_frame::<Envelope<Self>>(r)?.map(|e| (e.id, e.body)))
+    }
+
+    /// Like `read_from`, for servers running on tokio.
+    pub async fn read_from_async(r: impl AsyncRead + Unpin + Send) -> Result<Option<(u64, Self)>> {
+        Ok(read_frame_async::<Envelope<Self>>(r).await?.map(|e| (e.id, e.body)))
     }
 }
 
 impl Response {
-    pub async fn read_from(mut r: impl AsyncRead + Unpin + Send) -> Result<Self> {
-        let mut data = String::new();
-        r.read_to_string(&mut data).await?;
-        Ok(serde_json::from_str(&data)?)
+    /// Reads the next response and the id of its request, or `None` if the
+    /// peer closed the connection.
+    pub async fn read_from(r: impl AsyncRead + Unpin + Send) -> Result<Option<(u64, Self)>> {
+        Ok(read_frame_async::<Envelope<Self>>(r).await?.map(|e| (e.id, e.body)))
     }
 
-    pub fn write(&self, mut w: impl Write) -> Result<()> {
-        let data = serde_json::to_string(self)?;
-        w.write(data.as_bytes())?;
-        w.flush()?;
-        Ok(())
+    /// Writes the response to request `id`, leaving it to the caller to
+    /// flush `w`, possibly after more responses.
+    pub fn write(&self, id: u64, w: impl Write) -> Result<()> {
+        write_frame(w, &Envelope { id, body: self })
     }
-}
\ No newline at end of file
+
+    /// Like `write`, for servers running on tokio.
+    pub async fn write_async(&self, id: u64, w: impl AsyncWrite + Unpin + Send) -> Result<()> {
+        write_frame_async(w, &Envelope { id, body: self }).await
+    }
+}
+
+fn write_frame<T: Serialize>(mut w: impl Write, v: &T) -> Result<()> {
+    let data = serde_json::to_vec(v)?;
+    w.write_all(&(data.len() as u32).to_be_bytes())?;
+    w.write_all(&data)?;
+    Ok(())
+}
+
+async fn write_frame_async<T: Serialize>(mut w: impl AsyncWrite + Unpin + Send, v: &T) -> Result<()> {
+    let data = serde_json::to_vec(v)?;
+    w.write_all(&(data.len() as u32).to_be_bytes()).await?;
+    w.write_all(&data).await?;
+    Ok(())
+}
+
+fn read_frame<T: DeserializeOwned>(mut r: impl Read) -> Result<Option<T>> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    match r.read_exact(&mut header) {
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        res => res?,
+    }
+    let mut data = vec![0u8; frame_size(header)?];
+    r.read_exact(&mut data)?;
+    Ok(Some(serde_json::from_slice(&data)?))
+}
+
+async fn read_frame_async<T: DeserializeOwned>(mut r: impl AsyncRead + Unpin + Send) -> Result<Option<T>> {
+    let mut header = [0u8; FRAME_HEADER_SIZE];
+    match r.read_exact(&mut header).await {
+        Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        res => res?,
+    };
+    let mut data = vec![0u8; frame_size(header)?];
+    r.read_exact(&mut data).await?;
+    Ok(Some(serde_json::from_slice(&data)?))
+}
+
+fn frame_size(header: [u8; FRAME_HEADER_SIZE]) -> Result<usize> {
+    let len = u32::from_be_bytes(header);
+    if len > MAX_FRAME_SIZE {
+        Err(KvsError::StringError(format!("frame of {} bytes is too large", len)))?
+    }
+    Ok(len as usize)
+}
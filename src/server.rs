@@ -1,23 +1,39 @@
-use log::error;
-use log::warn;
+use log::{error, info, warn};
 
 use crate::engine::*;
 use crate::err::*;
 use crate::protocol::*;
 use crate::thread_pool::ThreadPool;
 
-use std::io::BufReader;
-use std::io::BufWriter;
-use std::net::{SocketAddr, TcpStream};
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{Shutdown, SocketAddr, TcpStream};
 use std::net::TcpListener;
+use std::sync::{self, Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{mpsc, watch, RwLock};
+use tokio::task;
+
+/// How long a server shutting down waits for the requests in flight,
+/// unless told otherwise.
+const DEFAULT_DRAIN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A server reading each connection on a thread of its own, and answering
+/// its requests on the pool. Pool workers are only taken while there are
+/// requests to answer, so idle clients do not hold them.
 pub struct KvsServer<E: KvsEngine, T: ThreadPool> {
     engine: E, 
-    thread_pool: T,
+    thread_pool: Arc<T>,
     listener: TcpListener,
+    shutdown: ShutdownHandle,
+    connections: Arc<Connections>,
+    drain_timeout: Duration,
 }
 
-impl<E: KvsEngine, T: ThreadPool> KvsServer<E, T> {
+impl<E: KvsEngine, T: ThreadPool + Send + Sync + 'static> KvsServer<E, T> {
     pub fn new(address: &str, engine: E, thread_pool: T) -> Result<Self> {
         let address = address.parse::<SocketAddr>()?;
         let listener = TcpListener::bind(address)?;
@@ -25,74 +41,430 @@ impl<E: KvsEngine, T: ThreadPool> KvsServer<E, T> {
         Ok(Self {
             engine,
             listener,
-            thread_pool
+            thread_pool: Arc::new(thread_pool),
+            shutdown: ShutdownHandle::new(),
+            connections: Arc::new(Connections::default()),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
         })
     }
 
-    pub fn run(&self) -> Result<()> {
+    /// Sets how long shutting down waits for the requests in flight before
+    /// dropping their connections.
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Returns a handle that makes `run` return.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Serves connections until shut down through a `ShutdownHandle`. It then
+    /// stops accepting and reading requests, answers the ones already read,
+    /// joins the pool workers and flushes the engine.
+    pub fn run(self) -> Result<()> {
+        let address = self.listener.local_addr()?;
+        let shutdown = self.shutdown.clone();
+        let connections = self.connections.clone();
+        thread::spawn(move || {
+            shutdown.wait();
+            connections.stop_reading();
+            // wakes the accept loop up
+            let _ = TcpStream::connect(address);
+        });
+
         for stream in self.listener.incoming() {
-            match stream {
-                Ok(stream) => {
+            if self.shutdown.is_shutdown() {
+                break;
+            }
+            match stream.and_then(|stream| self.connections.register(stream)) {
+                Ok((stream, registration)) => {
                     let engine = self.engine.clone();
-                    self.thread_pool.spawn(move || {
-                        match handle_client(engine, stream) {
-                            Ok(()) => {},
-                            Err(e) => error!("stream handle error {}.", e),
+                    let pool = Arc::clone(&self.thread_pool);
+                    thread::spawn(move || {
+                        if let Err(e) = handle_client(engine, stream, pool, registration) {
+                            error!("stream handle error {}.", e);
                         }
-                    })
+                    });
                 },
                 Err(e) => error!("stream handle error {}.", e),
             }
         }
+        drop(self.listener);
 
-        Ok(())
+        info!("Shutting down");
+        let deadline = Instant::now() + self.drain_timeout;
+        if !self.connections.drain(self.drain_timeout) {
+            warn!("Requests still running after {:?}, dropping their connections", self.drain_timeout);
+            self.connections.close();
+        }
+        // connections hold the pool until their last request is answered
+        match Arc::try_unwrap(self.thread_pool) {
+            Ok(pool) => {
+                let (joined, join_done) = sync::mpsc::channel();
+                thread::spawn(move || {
+                    pool.join();
+                    let _ = joined.send(());
+                });
+                let left = deadline.saturating_duration_since(Instant::now());
+                if join_done.recv_timeout(left).is_err() {
+                    warn!("Pool workers still running after {:?}, leaving them behind", self.drain_timeout);
+                }
+            },
+            Err(_) => warn!("Requests still running, leaving the pool workers behind"),
+        }
+        self.engine.flush()
     }
 }
 
-fn handle_client<E: KvsEngine>(engine: E, stream: TcpStream) -> Result<()> {
-    let mut reader = BufReader::new(&stream);
-    let mut writer = BufWriter::new(&stream);
-    match Request::read_from(&mut reader)? {
-        Request::Set(k, v) => {
-            match engine.set(k, v) {
-                Err(e) => {
-                    error!("{}", e);
-                    Response::Error(e.to_string()).write(&mut writer)?;
-                }
-                _ => {
-                    Response::Ok.write(&mut writer)?;
-                }
+/// Asks a `KvsServer` or `AsyncKvsServer` to shut down, from any thread.
+#[derive(Clone)]
+pub struct ShutdownHandle {
+    state: Arc<ShutdownState>,
+}
+
+struct ShutdownState {
+    requested: Mutex<bool>,
+    requested_cond: Condvar,
+    /// The same flag, for tasks on tokio to wait on.
+    watch: (watch::Sender<bool>, watch::Receiver<bool>),
+}
+
+impl ShutdownHandle {
+    fn new() -> Self {
+        Self {
+            state: Arc::new(ShutdownState {
+                requested: Mutex::new(false),
+                requested_cond: Condvar::new(),
+                watch: watch::channel(false),
+            }),
+        }
+    }
+
+    pub fn shutdown(&self) {
+        *self.state.requested.lock().unwrap() = true;
+        self.state.requested_cond.notify_all();
+        let _ = self.state.watch.0.send(true);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        *self.state.requested.lock().unwrap()
+    }
+
+    fn wait(&self) {
+        let mut requested = self.state.requested.lock().unwrap();
+        while !*requested {
+            requested = self.state.requested_cond.wait(requested).unwrap();
+        }
+    }
+
+    async fn wait_async(&self) {
+        let mut requested = self.state.watch.1.clone();
+        while !*requested.borrow() {
+            if requested.changed().await.is_err() {
+                return;
             }
-        },
-        Request::Get(k) => {
-            match engine.get(k) {
-                Err(e) => {
-                    error!("{}", e);
-                    Response::Error(e.to_string()).write(&mut writer)?;
-                }
-                Ok(value) => {
-                    Response::Get(value).write(&mut writer)?;
-                }
+        }
+    }
+}
+
+/// The connections a `KvsServer` is serving, so that shutting down can stop
+/// reading from them and wait for their handlers.
+#[derive(Default)]
+struct Connections {
+    state: Mutex<ConnectionsState>,
+    closed: Condvar,
+}
+
+#[derive(Default)]
+struct ConnectionsState {
+    streams: HashMap<u64, TcpStream>,
+    next_id: u64,
+    stopped: bool,
+}
+
+/// Keeps a connection registered until its handler is done with it.
+struct Registration {
+    connections: Arc<Connections>,
+    id: u64,
+}
+
+impl Connections {
+    fn register(self: &Arc<Self>, stream: TcpStream) -> io::Result<(TcpStream, Registration)> {
+        let mut state = self.state.lock().unwrap();
+        if state.stopped {
+            // accepted as the server was shutting down
+            stream.shutdown(Shutdown::Read)?;
+        }
+        let id = state.next_id;
+        state.next_id += 1;
+        state.streams.insert(id, stream.try_clone()?);
+        Ok((stream, Registration { connections: self.clone(), id }))
+    }
+
+    /// Makes every handler see the end of its connection once it has
+    /// answered the requests it already read.
+    fn stop_reading(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.stopped = true;
+        for stream in state.streams.values() {
+            let _ = stream.shutdown(Shutdown::Read);
+        }
+    }
+
+    /// Waits up to `timeout` for every handler to finish, returning whether
+    /// they all did.
+    fn drain(&self, timeout: Duration) -> bool {
+        let state = self.state.lock().unwrap();
+        let (state, _) = self.closed
+            .wait_timeout_while(state, timeout, |state| !state.streams.is_empty())
+            .unwrap();
+        state.streams.is_empty()
+    }
+
+    fn close(&self) {
+        for stream in self.state.lock().unwrap().streams.values() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        self.connections.state.lock().unwrap().streams.remove(&self.id);
+        self.connections.closed.notify_all();
+    }
+}
+
+/// A server accepting and serving connections on tokio. Engine calls run
+/// on tokio's blocking pool, so slow disk writes do not stall other clients.
+pub struct AsyncKvsServer<E: KvsEngine> {
+    engine: E,
+    listener: tokio::net::TcpListener,
+    shutdown: ShutdownHandle,
+    drain_timeout: Duration,
+}
+
+impl<E: KvsEngine> AsyncKvsServer<E> {
+    pub async fn new(address: &str, engine: E) -> Result<Self> {
+        let address = address.parse::<SocketAddr>()?;
+        let listener = tokio::net::TcpListener::bind(address).await?;
+
+        Ok(Self {
+            engine,
+            listener,
+            shutdown: ShutdownHandle::new(),
+            drain_timeout: DEFAULT_DRAIN_TIMEOUT,
+        })
+    }
+
+    /// See `KvsServer::with_drain_timeout`.
+    pub fn with_drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = timeout;
+        self
+    }
+
+    /// Returns a handle that makes `run` return.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Serves connections until shut down, then winds down like
+    /// `KvsServer::run`, flushing the engine once its calls have returned.
+    /// Connections still busy after the drain timeout are dropped along with
+    /// the runtime. Engine calls still running by then are not waited for:
+    /// the flush goes ahead without them, so their writes are only synced if
+    /// the sync policy of the engine does it.
+    pub async fn run(self) -> Result<()> {
+        // every connection holds a sender, so `recv` ends once all are done
+        let (running, mut done) = mpsc::channel::<()>(1);
+        // every engine call holds a read guard until it returns
+        let calls = Arc::new(RwLock::new(()));
+        loop {
+            tokio::select! {
+                res = self.listener.accept() => match res {
+                    Ok((stream, _)) => {
+                        let engine = self.engine.clone();
+                        let shutdown = self.shutdown.clone();
+                        let running = running.clone();
+                        let calls = calls.clone();
+                        tokio::spawn(async move {
+                            if let Err(e) = handle_client_async(engine, stream, calls, shutdown).await {
+                                error!("stream handle error {}.", e);
+                            }
+                            drop(running);
+                        });
+                    },
+                    Err(e) => error!("stream handle error {}.", e),
+                },
+                _ = self.shutdown.wait_async() => break,
             }
-        },
-        Request::Remove(k) => {
-            match engine.remove(k) {
-                Err(e) => {
-                    Response::Error(e.to_string()).write(&mut writer)?;
-                    match e {
-                        KvsError::NoEntryError => {
-                            warn!("{}", e);
-                        },
-                        _ => {
-                            error!("{}", e);
-                        },
-                    }
-                }
-                _ => {
-                    Response::Ok.write(&mut writer)?;
+        }
+        drop(self.listener);
+        drop(running);
+
+        info!("Shutting down");
+        let deadline = tokio::time::Instant::now() + self.drain_timeout;
+        if tokio::time::timeout_at(deadline, done.recv()).await.is_err() {
+            warn!("Requests still running after {:?}, dropping their connections", self.drain_timeout);
+        }
+        // held through the flush, so connections left running start no more calls
+        let idle = tokio::time::timeout_at(deadline, calls.write()).await;
+        if idle.is_err() {
+            warn!("Engine calls still running after {:?}, flushing without them", self.drain_timeout);
+        }
+        let engine = self.engine;
+        task::spawn_blocking(move || engine.flush())
+            .await
+            .map_err(io::Error::from)?
+    }
+}
+
+/// Reads the requests of a client until it closes the connection, queueing
+/// them for the pool to answer.
+fn handle_client<E, T>(engine: E, stream: TcpStream, pool: Arc<T>, registration: Registration) -> Result<()>
+where
+    E: KvsEngine,
+    T: ThreadPool + Send + Sync + 'static,
+{
+    let connection = Arc::new(Connection {
+        pool,
+        queue: Mutex::new((VecDeque::new(), false)),
+        serving: Mutex::new((engine, BufWriter::new(stream.try_clone()?))),
+        _registration: registration,
+    });
+    let mut reader = BufReader::new(&stream);
+    while let Some(request) = Request::read_from(&mut reader)? {
+        connection.push(request);
+    }
+    Ok(())
+}
+
+/// A connection of a `KvsServer`, shared by the thread reading its requests
+/// and the pool job answering them.
+struct Connection<E, T> {
+    pool: Arc<T>,
+    /// Requests read but not answered yet, and whether a job is answering them.
+    queue: Mutex<(VecDeque<(u64, Request)>, bool)>,
+    /// The engine handle and writer of the connection, for the job answering
+    /// its requests. Keeping one handle lets reads reuse the logs it opened.
+    serving: Mutex<(E, BufWriter<TcpStream>)>,
+    // dropped last, once the pool is no longer held
+    _registration: Registration,
+}
+
+impl<E, T> Connection<E, T>
+where
+    E: KvsEngine,
+    T: ThreadPool + Send + Sync + 'static,
+{
+    fn push(self: &Arc<Self>, request: (u64, Request)) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.0.push_back(request);
+        if !queue.1 {
+            queue.1 = true;
+            let connection = Arc::clone(self);
+            self.pool.spawn(move || connection.answer());
+        }
+    }
+
+    /// Answers the queued requests in order. The responses to requests the
+    /// client pipelined go out together, once the queue runs empty.
+    fn answer(&self) {
+        let mut serving = self.serving.lock().unwrap();
+        let (engine, writer) = &mut *serving;
+        loop {
+            let ((id, request), last) = {
+                let mut queue = self.queue.lock().unwrap();
+                match queue.0.pop_front() {
+                    Some(next) => (next, queue.0.is_empty()),
+                    None => {
+                        queue.1 = false;
+                        return;
+                    },
                 }
+            };
+            let mut res = respond(engine, request).write(id, &mut *writer);
+            if last && res.is_ok() {
+                res = writer.flush().map_err(KvsError::from);
             }
-        },
+            if let Err(e) = res {
+                error!("stream handle error {}.", e);
+            }
+        }
+    }
+}
+
+/// Like `handle_client`, for `AsyncKvsServer`.
+/// Stops reading requests once the server shuts down. The engine handle
+/// moves into each blocking call and back out with its response, so reads
+/// keep reusing the logs it opened.
+async fn handle_client_async<E: KvsEngine>(
+    mut engine: E,
+    stream: tokio::net::TcpStream,
+    calls: Arc<RwLock<()>>,
+    shutdown: ShutdownHandle,
+) -> Result<()> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = tokio::io::BufReader::new(reader);
+    let mut writer = tokio::io::BufWriter::new(writer);
+    loop {
+        let next = tokio::select! {
+            // requests already buffered still get answered
+            biased;
+            next = Request::read_from_async(&mut reader) => next?,
+            _ = shutdown.wait_async() => None,
+        };
+        let (id, request) = match next {
+            Some(next) => next,
+            None => break,
+        };
+        let call = calls.clone().read_owned().await;
+        let (returned, response) = task::spawn_blocking(move || {
+            let response = respond(&engine, request);
+            drop(call);
+            (engine, response)
+        })
+        .await
+        .map_err(io::Error::from)?;
+        engine = returned;
+        response.write_async(id, &mut writer).await?;
+        if reader.buffer().is_empty() {
+            writer.flush().await?;
+        }
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+fn respond<E: KvsEngine>(engine: &E, request: Request) -> Response {
+    let res = match request {
+        Request::Set(k, v) => engine.set(k, v).map(|_| Response::Ok),
+        Request::SetWithTtl(k, v, ttl) => engine.set_with_ttl(k, v, ttl).map(|_| Response::Ok),
+        Request::Expire(k, ttl) => engine.expire(k, ttl).map(|_| Response::Ok),
+        Request::Get(k) => engine.get(k).map(Response::Get),
+        Request::GetVersioned(k) => engine.get_versioned(k).map(Response::GetVersioned),
+        Request::GetRange(k, offset, len) => engine.get_range(k, offset, len).map(Response::Get),
+        Request::Remove(k) => engine.remove(k).map(|_| Response::Ok),
+        Request::CompareAndSwap(k, expected, new) => {
+            engine.compare_and_swap(k, expected, new).map(|_| Response::Ok)
+        },
+        Request::CompareVersionAndSwap(k, version, new) => {
+            engine.compare_version_and_swap(k, version, new).map(Response::Version)
+        },
+        Request::Incr(k, delta) => engine.incr(k, delta).map(Response::Counter),
+        Request::Append(k, suffix) => engine.append(k, suffix).map(Response::Length),
+        Request::Batch(batch) => engine.write_batch(batch).map(|_| Response::Ok),
+        Request::Scan(start, end, limit) => engine.scan((start, end), limit).map(Response::Scan),
+        Request::Compact => engine.compact().map(|_| Response::Ok),
+    };
+    res.unwrap_or_else(|e| {
+        let code = ErrorCode::of(&e);
+        match code {
+            ErrorCode::Internal => error!("{}", e),
+            // the client asked for something the store does not allow
+            _ => warn!("{}", e),
+        }
+        Response::Error(code, e.to_string())
+    })
+}
@@ -14,5 +14,8 @@ pub trait ThreadPool {
 
     fn spawn<F>(&self, job: F) 
     where F:FnOnce() + Send + 'static;
+
+    /// Waits for the jobs spawned so far to finish, then stops the workers.
+    fn join(self);
 }
 
@@ -1,20 +1,36 @@
-use std::thread;
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
 
 use super::ThreadPool;
 
 use crate::err::*;
-pub struct NaiveThreadPool;
+
+/// Runs every job on a thread of its own.
+pub struct NaiveThreadPool {
+    /// Threads that may still be running, pruned as new ones are spawned.
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
 
 impl ThreadPool for NaiveThreadPool {
     fn new(_threads: u64) -> Result<Self> 
     where Self: Sized
     {
-        Ok(NaiveThreadPool)
+        Ok(NaiveThreadPool {
+            threads: Mutex::new(Vec::new()),
+        })
     }
 
     fn spawn<F>(&self, job: F) 
     where F:FnOnce() + Send + 'static 
     {
-        thread::spawn(job);
+        let mut threads = self.threads.lock().unwrap();
+        threads.retain(|t| !t.is_finished());
+        threads.push(thread::spawn(job));
+    }
+
+    fn join(self) {
+        for t in self.threads.into_inner().unwrap() {
+            let _ = t.join();
+        }
     }
-}
\ No newline at end of file
+}
@@ -1,3 +1,7 @@
+use std::sync::Mutex;
+use std::sync::mpsc::{Receiver, channel};
+
+use crossbeam_utils::sync::WaitGroup;
 use rayon::ThreadPoolBuilder;
 
 use super::ThreadPool;
@@ -5,21 +9,48 @@ use crate::err::*;
 
 pub struct RayonThreadPool {
     pool: rayon::ThreadPool,
+    /// Held by every job until it is done.
+    jobs: WaitGroup,
+    /// Hears from each worker thread as it exits.
+    exits: Mutex<Receiver<()>>,
 }
 
 impl ThreadPool for RayonThreadPool{
     fn new(_threads: u64) -> Result<Self> 
     where Self: Sized {
+        let (exited, exits) = channel();
+        let exited = Mutex::new(exited);
         let pool = ThreadPoolBuilder::new()
             .num_threads(_threads as usize)
+            .exit_handler(move |_| {
+                let _ = exited.lock().unwrap().send(());
+            })
             .build()?;
         Ok(Self {
             pool, 
+            jobs: WaitGroup::new(),
+            exits: Mutex::new(exits),
         })
     }
 
     fn spawn<F>(&self, job: F) 
     where F:FnOnce() + Send + 'static {
-        self.pool.spawn(job);
+        let done = self.jobs.clone();
+        self.pool.spawn(move || {
+            job();
+            drop(done);
+        });
+    }
+
+    fn join(self) {
+        let RayonThreadPool { pool, jobs, exits } = self;
+        jobs.wait();
+        let threads = pool.current_num_threads();
+        // rayon stops its workers once the pool is dropped
+        drop(pool);
+        let exits = exits.into_inner().unwrap();
+        for _ in 0..threads {
+            let _ = exits.recv();
+        }
     }
 }
\ No newline at end of file
@@ -1,5 +1,5 @@
 use std::sync::atomic::AtomicU64;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, Condvar, Mutex};
 use std::thread;
 use std::sync::mpsc::{Sender, Receiver, channel};
 use std::sync::atomic::Ordering::SeqCst;
@@ -35,6 +35,8 @@ struct SharedQueueData {
     task_receiver: Mutex<Receiver<Task>>,
     threads_num: u64,
     threads_alive: AtomicU64,
+    /// Signalled whenever a worker exits for good.
+    exited: (Mutex<()>, Condvar),
 }
 
 struct Guard {
@@ -54,6 +56,7 @@ impl ThreadPool for SharedQueueThreadPool {
             threads_num,
             task_receiver,
             threads_alive,
+            exited: (Mutex::new(()), Condvar::new()),
         });
 
         let pool = SharedQueueThreadPool {
@@ -74,6 +77,18 @@ impl ThreadPool for SharedQueueThreadPool {
             error!("error occurs {}", e);
         };
     }
+
+    /// Closes the queue, so that workers exit once it is empty, and waits
+    /// for all of them.
+    fn join(self) {
+        let SharedQueueThreadPool { task_sender, data } = self;
+        drop(task_sender);
+        let (lock, exited) = &data.exited;
+        let mut guard = lock.lock().unwrap();
+        while data.threads_alive.load(SeqCst) > 0 {
+            guard = exited.wait(guard).unwrap();
+        }
+    }
 }
 
 impl Guard {
@@ -90,10 +105,15 @@ impl Guard {
 
 impl Drop for Guard {
     fn drop(&mut self) {
-        if thread::panicking() {
+        if !thread::panicking() {
+            // the queue is closed
+            let _guard = self.data.exited.0.lock().unwrap();
             self.data.threads_alive.fetch_sub(1, SeqCst);
+            self.data.exited.1.notify_all();
+            return;
         }
 
+        self.data.threads_alive.fetch_sub(1, SeqCst);
         while self.data.threads_alive.load(SeqCst) < self.data.threads_num {
             new_thread(self.data.clone());
         }
@@ -107,8 +127,9 @@ impl SharedQueueThreadPool {
 }
 
 fn new_thread(data: Arc<SharedQueueData>) {
+    // counted before it starts, so that a replacement is never spawned twice
+    data.threads_alive.fetch_add(1, SeqCst);
     thread::spawn(move || {
-        data.threads_alive.fetch_add(1, SeqCst);
         let guard = Guard::new(data.clone());
         guard.work();
         'exit:      
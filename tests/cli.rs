@@ -7,6 +7,9 @@ use std::thread;
 use std::time::Duration;
 use tempfile::TempDir;
 
+/// The extra `kvs-server` arguments of each way it can serve connections.
+const SERVER_MODES: [&[&str]; 2] = [&[], &["--async"]];
+
 // `kvs-client` with no args should exit with a non-zero code.
 #[test]
 fn client_cli_no_args() {
@@ -151,6 +154,17 @@ fn server_cli_version() {
         .stdout(contains(env!("CARGO_PKG_VERSION")));
 }
 
+#[test]
+fn server_cli_invalid_sync() {
+    let temp_dir = TempDir::new().unwrap();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--sync", "sometimes", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+}
+
 #[test]
 fn cli_log_configuration() {
     let temp_dir = TempDir::new().unwrap();
@@ -164,6 +178,7 @@ fn cli_log_configuration() {
         .unwrap();
     thread::sleep(Duration::from_secs(1));
     child.kill().expect("server exited before killed");
+    let _ = child.wait();
 
     let content = fs::read_to_string(&stderr_path).expect("unable to read from stderr file");
     assert!(content.contains(env!("CARGO_PKG_VERSION")));
@@ -184,6 +199,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        let _ = child.wait();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "kvs", "--addr", "127.0.0.1:4003"])
@@ -203,6 +219,7 @@ fn cli_wrong_engine() {
             .unwrap();
         thread::sleep(Duration::from_secs(1));
         child.kill().expect("server exited before killed");
+        let _ = child.wait();
 
         let mut cmd = Command::cargo_bin("kvs-server").unwrap();
         cmd.args(&["--engine", "sled", "--addr", "127.0.0.1:4003"])
@@ -212,18 +229,20 @@ fn cli_wrong_engine() {
     }
 }
 
-fn cli_access_server(engine: &str, addr: &str) {
+fn cli_access_server(engine: &str, addr: &str, mode: &[&str]) {
     let (sender, receiver) = mpsc::sync_channel(0);
     let temp_dir = TempDir::new().unwrap();
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
         .args(&["--engine", engine, "--addr", addr])
+        .args(mode)
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        let _ = child.wait();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -299,12 +318,14 @@ fn cli_access_server(engine: &str, addr: &str) {
     let mut server = Command::cargo_bin("kvs-server").unwrap();
     let mut child = server
         .args(&["--engine", engine, "--addr", addr])
+        .args(mode)
         .current_dir(&temp_dir)
         .spawn()
         .unwrap();
     let handle = thread::spawn(move || {
         let _ = receiver.recv(); // wait for main thread to finish
         child.kill().expect("server exited before killed");
+        let _ = child.wait();
     });
     thread::sleep(Duration::from_secs(1));
 
@@ -328,10 +349,502 @@ fn cli_access_server(engine: &str, addr: &str) {
 
 #[test]
 fn cli_access_server_kvs_engine() {
-    cli_access_server("kvs", "127.0.0.1:4004");
+    cli_access_server("kvs", "127.0.0.1:4004", &[]);
 }
 
 #[test]
 fn cli_access_server_sled_engine() {
-    cli_access_server("sled", "127.0.0.1:4005");
+    cli_access_server("sled", "127.0.0.1:4005", &[]);
+}
+
+#[test]
+fn cli_access_async_server_kvs_engine() {
+    cli_access_server("kvs", "127.0.0.1:4015", &["--async"]);
+}
+
+#[test]
+fn cli_access_async_server_sled_engine() {
+    cli_access_server("sled", "127.0.0.1:4016", &["--async"]);
+}
+
+#[test]
+fn cli_scan() {
+    for mode in &SERVER_MODES {
+        let (sender, receiver) = mpsc::sync_channel(0);
+        let temp_dir = TempDir::new().unwrap();
+        let addr = "127.0.0.1:4006";
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(&["--engine", "kvs", "--addr", addr])
+            .args(*mode)
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        let handle = thread::spawn(move || {
+            let _ = receiver.recv(); // wait for main thread to finish
+            child.kill().expect("server exited before killed");
+            let _ = child.wait();
+        });
+        thread::sleep(Duration::from_secs(1));
+
+        for (k, v) in &[("a:1", "x"), ("a:2", "y"), ("b:1", "z")] {
+            Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(&["set", k, v, "--addr", addr])
+                .current_dir(&temp_dir)
+                .assert()
+                .success();
+        }
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["scan", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("a:1 x\na:2 y\nb:1 z\n");
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["scan", "--prefix", "a:", "--limit", "1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("a:1 x\n");
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["scan", "a:2", "b:1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("a:2 y\n");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn cli_compact() {
+    for mode in &SERVER_MODES {
+        let (sender, receiver) = mpsc::sync_channel(0);
+        let temp_dir = TempDir::new().unwrap();
+        let addr = "127.0.0.1:4008";
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(&["--engine", "kvs", "--addr", addr])
+            .args(*mode)
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        let handle = thread::spawn(move || {
+            let _ = receiver.recv(); // wait for main thread to finish
+            child.kill().expect("server exited before killed");
+            let _ = child.wait();
+        });
+        thread::sleep(Duration::from_secs(1));
+
+        for v in &["value1", "value2"] {
+            Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(&["set", "key1", v, "--addr", addr])
+                .current_dir(&temp_dir)
+                .assert()
+                .success();
+        }
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["compact", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout(is_empty());
+        assert!(!temp_dir.path().join("1.log").exists());
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value2\n");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn cli_data_dir() {
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let temp_dir = TempDir::new().unwrap();
+    let data_dir = temp_dir.path().join("data");
+    let addr = "127.0.0.1:4009";
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr, "--data-dir"])
+        .arg(&data_dir)
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        let _ = child.wait();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    assert!(data_dir.join("MANIFEST").exists());
+    assert!(data_dir.join("1.log").exists());
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    // the directory now belongs to kvs
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "sled", "--addr", addr, "--data-dir"])
+        .arg(&data_dir)
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("WrongEngine"));
+}
+
+#[test]
+fn cli_read_only() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4010";
+
+    // a read-only server cannot serve sled, nor upgrade a store
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--engine", "sled", "--read-only", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+    Command::cargo_bin("kvs-server")
+        .unwrap()
+        .args(&["--read-only", "--upgrade", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure();
+
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        let _ = child.wait();
+    });
+    thread::sleep(Duration::from_secs(1));
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success();
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+
+    let (sender, receiver) = mpsc::sync_channel(0);
+    let mut server = Command::cargo_bin("kvs-server").unwrap();
+    let mut child = server
+        .args(&["--engine", "kvs", "--read-only", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    let handle = thread::spawn(move || {
+        let _ = receiver.recv(); // wait for main thread to finish
+        child.kill().expect("server exited before killed");
+        let _ = child.wait();
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["get", "key1", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    Command::cargo_bin("kvs-client")
+        .unwrap()
+        .args(&["set", "key1", "value2", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("read-only"));
+
+    sender.send(()).unwrap();
+    handle.join().unwrap();
+}
+
+// `kvs-client set --ttl` should make the key expire on both engines
+#[test]
+fn cli_set_ttl() {
+    for engine in &["kvs", "sled"] {
+        for mode in &SERVER_MODES {
+            let temp_dir = TempDir::new().unwrap();
+            let addr = "127.0.0.1:4011";
+            let (sender, receiver) = mpsc::sync_channel(0);
+            let mut server = Command::cargo_bin("kvs-server").unwrap();
+            let mut child = server
+                .args(&["--engine", engine, "--addr", addr])
+                .args(*mode)
+                .current_dir(&temp_dir)
+                .spawn()
+                .unwrap();
+            let handle = thread::spawn(move || {
+                let _ = receiver.recv(); // wait for main thread to finish
+                child.kill().expect("server exited before killed");
+                let _ = child.wait();
+            });
+            thread::sleep(Duration::from_secs(1));
+
+            Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(&["set", "key1", "value1", "--ttl", "1", "--addr", addr])
+                .current_dir(&temp_dir)
+                .assert()
+                .success()
+                .stdout(is_empty());
+            Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(&["set", "key2", "value2", "--ttl", "soon", "--addr", addr])
+                .current_dir(&temp_dir)
+                .assert()
+                .failure();
+            Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(&["get", "key1", "--addr", addr])
+                .current_dir(&temp_dir)
+                .assert()
+                .success()
+                .stdout("value1\n");
+
+            thread::sleep(Duration::from_millis(1500));
+            Command::cargo_bin("kvs-client")
+                .unwrap()
+                .args(&["get", "key1", "--addr", addr])
+                .current_dir(&temp_dir)
+                .assert()
+                .success()
+                .stdout("Key not found\n");
+
+            sender.send(()).unwrap();
+            handle.join().unwrap();
+        }
+    }
+}
+
+// `kvs-client cas` should only write over the expected value
+#[test]
+fn cli_cas() {
+    for mode in &SERVER_MODES {
+        let temp_dir = TempDir::new().unwrap();
+        let addr = "127.0.0.1:4012";
+        let (sender, receiver) = mpsc::sync_channel(0);
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(&["--engine", "kvs", "--addr", addr])
+            .args(*mode)
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        let handle = thread::spawn(move || {
+            let _ = receiver.recv(); // wait for main thread to finish
+            child.kill().expect("server exited before killed");
+            let _ = child.wait();
+        });
+        thread::sleep(Duration::from_secs(1));
+
+        let cas = |args: &[&str]| {
+            let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+            cmd.arg("cas").args(args).args(&["--addr", addr]).current_dir(&temp_dir);
+            cmd.assert()
+        };
+        cas(&["key1", "value1"]).success().stdout(is_empty());
+        cas(&["key1", "value2"]).failure().stderr(contains("expected"));
+        cas(&["key1", "value2", "--expect", "value3"]).failure();
+        cas(&["key1", "value2", "--expect", "value1"]).success();
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value2\n");
+
+        cas(&["key1", "--expect", "value2"]).success();
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("Key not found\n");
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+// `kvs-client incr` and `decr` should print the new value, and refuse
+// values that are not numbers
+#[test]
+fn cli_incr_decr() {
+    for mode in &SERVER_MODES {
+        let temp_dir = TempDir::new().unwrap();
+        let addr = "127.0.0.1:4013";
+        let (sender, receiver) = mpsc::sync_channel(0);
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(&["--engine", "kvs", "--addr", addr])
+            .args(*mode)
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        let handle = thread::spawn(move || {
+            let _ = receiver.recv(); // wait for main thread to finish
+            child.kill().expect("server exited before killed");
+            let _ = child.wait();
+        });
+        thread::sleep(Duration::from_secs(1));
+
+        let client = |args: &[&str]| {
+            let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+            cmd.args(args).args(&["--addr", addr]).current_dir(&temp_dir);
+            cmd.assert()
+        };
+        client(&["incr", "counter"]).success().stdout("1\n");
+        client(&["incr", "counter", "10"]).success().stdout("11\n");
+        client(&["decr", "counter", "3"]).success().stdout("8\n");
+        client(&["decr", "counter"]).success().stdout("7\n");
+        client(&["incr", "counter", "many"]).failure();
+        client(&["get", "counter"]).success().stdout("7\n");
+
+        client(&["set", "key1", "value1"]).success();
+        client(&["incr", "key1"]).failure().stderr(contains("not an integer"));
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+// `kvs-client append` should print the length of the new value
+#[test]
+fn cli_append() {
+    for mode in &SERVER_MODES {
+        let temp_dir = TempDir::new().unwrap();
+        let addr = "127.0.0.1:4014";
+        let (sender, receiver) = mpsc::sync_channel(0);
+        let mut server = Command::cargo_bin("kvs-server").unwrap();
+        let mut child = server
+            .args(&["--engine", "kvs", "--addr", addr])
+            .args(*mode)
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        let handle = thread::spawn(move || {
+            let _ = receiver.recv(); // wait for main thread to finish
+            child.kill().expect("server exited before killed");
+            let _ = child.wait();
+        });
+        thread::sleep(Duration::from_secs(1));
+
+        let client = |args: &[&str]| {
+            let mut cmd = Command::cargo_bin("kvs-client").unwrap();
+            cmd.args(args).args(&["--addr", addr]).current_dir(&temp_dir);
+            cmd.assert()
+        };
+        client(&["append", "key1", "value"]).success().stdout("5\n");
+        client(&["append", "key1", "1"]).success().stdout("6\n");
+        client(&["get", "key1"]).success().stdout("value1\n");
+        client(&["append", "key1"]).failure();
+
+        sender.send(()).unwrap();
+        handle.join().unwrap();
+    }
+}
+
+// SIGTERM should make `kvs-server` exit cleanly, even with a client still
+// connected, keeping what was written
+#[cfg(unix)]
+#[test]
+fn cli_graceful_shutdown() {
+    for mode in &SERVER_MODES {
+        let temp_dir = TempDir::new().unwrap();
+        let addr = "127.0.0.1:4017";
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .args(*mode)
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["set", "key1", "value1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success();
+        let _idle = std::net::TcpStream::connect(addr).unwrap();
+
+        Command::new("kill")
+            .args(&["-TERM", &child.id().to_string()])
+            .assert()
+            .success();
+        let mut status = None;
+        for _ in 0..50 {
+            status = child.try_wait().unwrap();
+            if status.is_some() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        match status {
+            Some(status) => assert!(status.success()),
+            None => {
+                child.kill().unwrap();
+                panic!("server did not shut down");
+            },
+        }
+
+        let mut child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .args(*mode)
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        Command::cargo_bin("kvs-client")
+            .unwrap()
+            .args(&["get", "key1", "--addr", addr])
+            .current_dir(&temp_dir)
+            .assert()
+            .success()
+            .stdout("value1\n");
+        child.kill().expect("server exited before killed");
+        let _ = child.wait();
+    }
 }
@@ -0,0 +1,158 @@
+use std::io::Write;
+use std::net::TcpStream;
+use std::process::{Child, Command};
+use std::thread;
+use std::time::Duration;
+
+use assert_cmd::prelude::*;
+use kvs::client::KvsClient;
+use kvs::protocol::{ErrorCode, Request, Response};
+use kvs::server::KvsServer;
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{KvStore, KvsError, Result};
+use tempfile::TempDir;
+
+/// A `kvs-server` running in a temporary directory, killed once dropped.
+struct Server {
+    child: Child,
+    _dir: TempDir,
+}
+
+impl Server {
+    fn start(addr: &str) -> Self {
+        let dir = TempDir::new().unwrap();
+        let child = Command::cargo_bin("kvs-server")
+            .unwrap()
+            .args(&["--engine", "kvs", "--addr", addr])
+            .current_dir(dir.path())
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        Self { child, _dir: dir }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+// One client should be able to send any number of requests over its connection
+#[tokio::test]
+async fn client_reuses_connection() -> Result<()> {
+    let addr = "127.0.0.1:4101";
+    let _server = Server::start(addr);
+
+    let mut client = KvsClient::new(addr.parse()?).await?;
+    for i in 0..100 {
+        client.set(format!("key{}", i).into_bytes(), format!("value{}", i).into_bytes()).await?;
+    }
+    for i in 0..100 {
+        let value = client.get(format!("key{}", i).into_bytes()).await?;
+        assert_eq!(value, Some(format!("value{}", i).into_bytes()));
+    }
+    client.remove(b"key0".to_vec()).await?;
+    assert_eq!(client.get(b"key0".to_vec()).await?, None);
+    assert!(matches!(client.remove(b"key0".to_vec()).await, Err(KvsError::NoEntryError)));
+    assert_eq!(client.incr(b"counter".to_vec(), 2).await?, 2);
+    Ok(())
+}
+
+// A connection sending garbage should be dropped without taking the server down
+#[tokio::test]
+async fn server_survives_bad_frames() -> Result<()> {
+    let addr = "127.0.0.1:4102";
+    let _server = Server::start(addr);
+
+    let mut stream = TcpStream::connect(addr)?;
+    stream.write_all(b"GET / HTTP/1.1\r\n\r\n")?;
+    drop(stream);
+
+    let mut client = KvsClient::new(addr.parse()?).await?;
+    client.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    assert_eq!(client.get(b"key1".to_vec()).await?, Some(b"value1".to_vec()));
+    Ok(())
+}
+
+// Requests sent before any response arrives should each get their own back
+#[tokio::test]
+async fn client_pipelines_requests() -> Result<()> {
+    let addr = "127.0.0.1:4103";
+    let _server = Server::start(addr);
+
+    let mut client = KvsClient::new(addr.parse()?).await?;
+    let mut pending = Vec::new();
+    for i in 0..100 {
+        let key = format!("key{}", i).into_bytes();
+        pending.push(client.send(Request::Set(key.clone(), format!("value{}", i).into_bytes())).await?);
+        pending.push(client.send(Request::Get(key)).await?);
+    }
+    pending.push(client.send(Request::Remove(b"missing".to_vec())).await?);
+
+    // Awaited backwards, so none of them is simply the next one read
+    let mut responses = Vec::new();
+    for response in pending.into_iter().rev() {
+        responses.push(response.await?);
+    }
+    responses.reverse();
+    assert!(matches!(responses.pop(), Some(Response::Error(ErrorCode::NotFound, _))));
+    for (i, pair) in responses.chunks(2).enumerate() {
+        assert!(matches!(pair[0], Response::Ok));
+        match &pair[1] {
+            Response::Get(value) => assert_eq!(value.as_deref(), Some(format!("value{}", i).as_bytes())),
+            _ => panic!("unexpected response"),
+        }
+    }
+    Ok(())
+}
+
+// Errors should come back from the server as the matching `KvsError`
+#[tokio::test]
+async fn client_sees_error_kinds() -> Result<()> {
+    let addr = "127.0.0.1:4104";
+    let _server = Server::start(addr);
+
+    let mut client = KvsClient::new(addr.parse()?).await?;
+    client.set(b"key1".to_vec(), b"value1".to_vec()).await?;
+    assert!(matches!(
+        client.compare_and_swap(b"key1".to_vec(), None, Some(b"value2".to_vec())).await,
+        Err(KvsError::Conflict)
+    ));
+    assert!(matches!(client.incr(b"key1".to_vec(), 1).await, Err(KvsError::NotANumber)));
+    client.set(b"counter".to_vec(), i64::MAX.to_string().into_bytes()).await?;
+    assert!(matches!(client.incr(b"counter".to_vec(), 1).await, Err(KvsError::Overflow)));
+    assert!(matches!(client.expire(b"missing".to_vec(), Duration::from_secs(1)).await, Err(KvsError::NoEntryError)));
+    Ok(())
+}
+
+// Clients staying connected should not hold pool workers, so more of them
+// than the pool has threads are all served
+#[tokio::test]
+async fn more_clients_than_pool_threads() -> Result<()> {
+    let addr = "127.0.0.1:4105";
+    let dir = TempDir::new().unwrap();
+    let server = KvsServer::new(addr, KvStore::open(dir.path())?, SharedQueueThreadPool::new(2)?)?;
+    let shutdown = server.shutdown_handle();
+    let running = thread::spawn(move || server.run());
+
+    let mut clients = Vec::new();
+    for i in 0..4 {
+        let mut client = KvsClient::new(addr.parse()?).await?;
+        let key = format!("key{}", i).into_bytes();
+        let served = async {
+            client.set(key.clone(), b"value".to_vec()).await?;
+            client.get(key).await
+        };
+        let value = tokio::time::timeout(Duration::from_secs(5), served)
+            .await
+            .unwrap_or_else(|_| panic!("client {} was not served", i))?;
+        assert_eq!(value, Some(b"value".to_vec()));
+        clients.push(client);
+    }
+    drop(clients);
+
+    shutdown.shutdown();
+    running.join().unwrap()
+}
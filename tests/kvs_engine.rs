@@ -0,0 +1,1012 @@
+use std::ops::Bound;
+
+use std::fs::{self, OpenOptions};
+use std::thread;
+use std::time::Duration;
+
+use kvs::engine::{KvStoreOptions, LogCodec, Manifest, SledKvsEngine, SyncPolicy, WriteBatch};
+use kvs::{KvStore, KvsEngine, KvsError, Result};
+use tempfile::TempDir;
+
+fn kv(k: &str, v: &str) -> (Vec<u8>, Vec<u8>) {
+    (k.as_bytes().to_vec(), v.as_bytes().to_vec())
+}
+
+fn binary_values<E: KvsEngine>(engine: E) -> Result<()> {
+    let key = vec![0u8, 159, 146, 150, 255];
+    let value: Vec<u8> = (0..=255).collect();
+
+    engine.set(key.clone(), value.clone())?;
+    assert_eq!(engine.get(key.clone())?, Some(value));
+    engine.set(key.clone(), vec![])?;
+    assert_eq!(engine.get(key.clone())?, Some(vec![]));
+    engine.remove(key.clone())?;
+    assert_eq!(engine.get(key)?, None);
+    Ok(())
+}
+
+// Should store and return arbitrary, non-UTF-8 bytes
+#[test]
+fn kvs_binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    binary_values(KvStore::open(temp_dir.path())?)?;
+
+    // Open from disk again and check persistent data
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(vec![0xff], vec![0x00, 0xfe])?;
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(vec![0xff])?, Some(vec![0x00, 0xfe]));
+    Ok(())
+}
+
+#[test]
+fn sled_binary_values() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    binary_values(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn scan_ranges<E: KvsEngine>(engine: E) -> Result<()> {
+    for (k, v) in &[("user:1", "a"), ("user:2", "b"), ("user:3", "c"), ("users", "d"), ("zone", "e")] {
+        engine.set(k.as_bytes().to_vec(), v.as_bytes().to_vec())?;
+    }
+    engine.remove(b"user:2".to_vec())?;
+
+    assert_eq!(
+        engine.prefix(b"user:".to_vec(), None)?,
+        vec![kv("user:1", "a"), kv("user:3", "c")]
+    );
+    assert_eq!(
+        engine.scan(b"user:3".to_vec()..b"zone".to_vec(), None)?,
+        vec![kv("user:3", "c"), kv("users", "d")]
+    );
+    assert_eq!(
+        engine.scan((Bound::Excluded(b"user:1".to_vec()), Bound::Unbounded), Some(2))?,
+        vec![kv("user:3", "c"), kv("users", "d")]
+    );
+    assert_eq!(engine.scan(.., Some(1))?, vec![kv("user:1", "a")]);
+    assert!(engine.prefix(b"nothing".to_vec(), None)?.is_empty());
+    Ok(())
+}
+
+// Should list keys in order, honouring range bounds, prefixes and limits
+#[test]
+fn kvs_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_ranges(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_scan() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    scan_ranges(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn batch_writes<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.set(b"a".to_vec(), b"1".to_vec())?;
+    engine.set(b"b".to_vec(), b"2".to_vec())?;
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"c".to_vec(), b"3".to_vec())
+        .remove(b"a".to_vec())
+        .set(b"b".to_vec(), b"4".to_vec())
+        .remove(b"missing".to_vec());
+    engine.write_batch(batch)?;
+
+    assert_eq!(engine.scan(.., None)?, vec![kv("b", "4"), kv("c", "3")]);
+    Ok(())
+}
+
+#[test]
+fn kvs_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    batch_writes(KvStore::open(temp_dir.path())?)?;
+
+    // Open from disk again and check persistent data
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan(.., None)?, vec![kv("b", "4"), kv("c", "3")]);
+    Ok(())
+}
+
+#[test]
+fn sled_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    batch_writes(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// A batch whose tail never reached the log should be dropped as a whole
+#[test]
+fn kvs_unfinished_batch_is_discarded() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"a".to_vec(), b"1".to_vec())?;
+    let mut batch = WriteBatch::new();
+    batch.set(b"b".to_vec(), b"2".to_vec()).set(b"c".to_vec(), b"3".to_vec());
+    store.write_batch(batch)?;
+    drop(store);
+
+    // cut the log right before the last entry of the batch
+    let log = temp_dir.path().join("1.log");
+    let content = fs::read(&log)?;
+    let last = content.windows(2).rposition(|w| w == b"{\"").unwrap();
+    OpenOptions::new().write(true).open(&log)?.set_len(last as u64)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan(.., None)?, vec![kv("a", "1")]);
+    store.set(b"d".to_vec(), b"4".to_vec())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan(.., None)?, vec![kv("a", "1"), kv("d", "4")]);
+    Ok(())
+}
+
+// Every sync policy should keep data readable and persistent
+#[test]
+fn kvs_sync_policies() -> Result<()> {
+    let policies = [
+        SyncPolicy::Never,
+        SyncPolicy::EveryWrite,
+        SyncPolicy::EveryN(3),
+        SyncPolicy::Interval(Duration::from_millis(10)),
+    ];
+    for sync in policies.iter() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let store = KvStore::open_with_options(temp_dir.path(), KvStoreOptions { sync: *sync, ..KvStoreOptions::default() })?;
+        for i in 0..10 {
+            store.set(format!("key{}", i).into_bytes(), format!("value{}", i).into_bytes())?;
+        }
+        store.remove(b"key0".to_vec())?;
+        // give the interval syncer a chance to run
+        thread::sleep(Duration::from_millis(30));
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get(b"key0".to_vec())?, None);
+        assert_eq!(store.get(b"key9".to_vec())?, Some(b"value9".to_vec()));
+    }
+    Ok(())
+}
+
+#[test]
+fn parse_sync_policy() {
+    assert_eq!("never".parse::<SyncPolicy>().unwrap(), SyncPolicy::Never);
+    assert_eq!("always".parse::<SyncPolicy>().unwrap(), SyncPolicy::EveryWrite);
+    assert_eq!("every:16".parse::<SyncPolicy>().unwrap(), SyncPolicy::EveryN(16));
+    assert_eq!(
+        "interval:200".parse::<SyncPolicy>().unwrap(),
+        SyncPolicy::Interval(Duration::from_millis(200))
+    );
+    for invalid in &["", "every", "every:0", "every:x", "interval:", "sometimes"] {
+        assert!(invalid.parse::<SyncPolicy>().is_err());
+    }
+}
+
+// Concurrent writers share group commits; each of them must still see its
+// own writes applied in order
+#[test]
+fn kvs_concurrent_writers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions { sync: SyncPolicy::EveryWrite, ..KvStoreOptions::default() };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|t| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..100 {
+                    let key = format!("key{}-{}", t, i).into_bytes();
+                    store.set(key.clone(), b"first".to_vec())?;
+                    store.remove(key.clone())?;
+                    assert!(store.remove(key.clone()).is_err());
+                    store.set(key, format!("value{}", i).into_bytes())?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan(.., None)?.len(), 800);
+    for t in 0..8 {
+        for i in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}-{}", t, i).into_bytes())?,
+                Some(format!("value{}", i).into_bytes())
+            );
+        }
+    }
+    Ok(())
+}
+
+// Overwriting keys should compact the logs in the background, without
+// losing the writes made while the compaction runs
+#[test]
+fn kvs_background_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let readers: Vec<_> = (0..2)
+        .map(|_| {
+            let store = store.clone();
+            thread::spawn(move || -> Result<()> {
+                for i in 0..2000 {
+                    store.get(format!("key{}", i % 100).into_bytes())?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+
+    for iter in 0..20 {
+        for key_id in 0..100 {
+            let mut value = format!("{}:", iter).into_bytes();
+            value.resize(1000, b'x');
+            store.set(format!("key{}", key_id).into_bytes(), value)?;
+        }
+    }
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+    // dropping the last handle waits for a running compaction
+    drop(store);
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..100 {
+        let value = store.get(format!("key{}", key_id).into_bytes())?.unwrap();
+        assert!(value.starts_with(b"19:"));
+    }
+    Ok(())
+}
+
+// Removed keys should count towards the garbage threshold, just like
+// overwritten ones
+#[test]
+fn kvs_compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions { compaction_threshold: 4096, ..KvStoreOptions::default() };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    for i in 0..100 {
+        store.set(format!("key{}", i).into_bytes(), vec![b'x'; 100])?;
+    }
+    for i in 0..50 {
+        store.remove(format!("key{}", i).into_bytes())?;
+    }
+    drop(store);
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan(.., None)?.len(), 50);
+    assert_eq!(store.get(b"key0".to_vec())?, None);
+    assert_eq!(store.get(b"key99".to_vec())?, Some(vec![b'x'; 100]));
+    Ok(())
+}
+
+#[test]
+fn kvs_compaction_ratio() -> Result<()> {
+    let options = || KvStoreOptions {
+        compaction_threshold: u64::MAX,
+        compaction_ratio: Some(0.5),
+        ..KvStoreOptions::default()
+    };
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i).into_bytes(), b"value".to_vec())?;
+    }
+    // overwriting a few keys keeps the garbage below the ratio
+    for i in 0..3 {
+        store.set(format!("key{}", i).into_bytes(), b"value".to_vec())?;
+    }
+    drop(store);
+    assert!(temp_dir.path().join("1.log").exists());
+
+    // the garbage replayed on open counts as well
+    let store = KvStore::open_with_options(temp_dir.path(), options())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i).into_bytes(), b"value".to_vec())?;
+    }
+    drop(store);
+    assert!(!temp_dir.path().join("1.log").exists());
+    Ok(())
+}
+
+// A compaction that fails should not be retried by every later write, each
+// retry rolling over to a new log
+#[test]
+fn kvs_failed_compaction_backs_off() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions { compaction_threshold: 4096, ..KvStoreOptions::default() };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.append(b"chained".to_vec(), b"value".to_vec())?;
+    store.append(b"chained".to_vec(), b"suffix".to_vec())?;
+
+    // damage the appended suffix, which compaction reads back to rewrite
+    // the value whole
+    let path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&path)?;
+    let last = log.len() - 2;
+    log[last] ^= 0x01;
+    fs::write(&path, &log)?;
+
+    let log_count = || -> Result<usize> {
+        Ok(dir_content(&temp_dir)?.iter().filter(|(name, _)| name.ends_with(".log")).count())
+    };
+    for _ in 0..500 {
+        store.set(b"other".to_vec(), vec![b'x'; 100])?;
+    }
+    assert_eq!(log_count()?, 2);
+    assert!(store.compact().is_err());
+
+    // once the damaged value is overwritten, compacting works again
+    store.set(b"chained".to_vec(), b"value".to_vec())?;
+    store.compact()?;
+    assert_eq!(log_count()?, 2);
+    assert_eq!(store.get(b"chained".to_vec())?, Some(b"value".to_vec()));
+    Ok(())
+}
+
+// Once the index points into the compacted log, failing to delete an old
+// log should not undo the compaction and lose what it moved
+#[test]
+fn kvs_compaction_survives_failed_log_deletion() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i).into_bytes(), format!("value{}", i).into_bytes())?;
+    }
+    // a directory in place of the hint of 1.log cannot be removed as a file
+    fs::create_dir_all(temp_dir.path().join("1.hint").join("busy"))?;
+    store.compact()?;
+    for i in 0..10 {
+        assert_eq!(store.get(format!("key{}", i).into_bytes())?, Some(format!("value{}", i).into_bytes()));
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan(.., None)?.len(), 10);
+    assert_eq!(store.get(b"key9".to_vec())?, Some(b"value9".to_vec()));
+    Ok(())
+}
+
+fn manual_compaction<E: KvsEngine>(engine: E) -> Result<()> {
+    for iter in 0..5 {
+        for key_id in 0..10 {
+            engine.set(format!("key{}", key_id).into_bytes(), format!("value{}", iter).into_bytes())?;
+        }
+    }
+    engine.remove(b"key0".to_vec())?;
+    engine.compact()?;
+    engine.compact()?;
+
+    assert_eq!(engine.get(b"key0".to_vec())?, None);
+    assert_eq!(engine.get(b"key9".to_vec())?, Some(b"value4".to_vec()));
+    Ok(())
+}
+
+// A forced compaction should drop the old logs right away, whatever the
+// thresholds say
+#[test]
+fn kvs_manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    manual_compaction(store.clone())?;
+    assert!(!temp_dir.path().join("1.log").exists());
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan(.., None)?.len(), 9);
+    assert_eq!(store.get(b"key9".to_vec())?, Some(b"value4".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn sled_manual_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    manual_compaction(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// A store should keep the codec it was created with, whatever it is
+// reopened with
+#[test]
+fn kvs_log_codecs() -> Result<()> {
+    for (codec, other) in &[(LogCodec::Json, LogCodec::Bincode), (LogCodec::Bincode, LogCodec::Json)] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = |codec| KvStoreOptions { codec, ..KvStoreOptions::default() };
+
+        let store = KvStore::open_with_options(temp_dir.path(), options(*codec))?;
+        binary_values(store.clone())?;
+        batch_writes(store.clone())?;
+        store.compact()?;
+        drop(store);
+
+        let store = KvStore::open_with_options(temp_dir.path(), options(*other))?;
+        assert_eq!(store.scan(.., None)?, vec![kv("b", "4"), kv("c", "3")]);
+        store.set(b"d".to_vec(), b"5".to_vec())?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.scan(.., None)?, vec![kv("b", "4"), kv("c", "3"), kv("d", "5")]);
+    }
+    Ok(())
+}
+
+// Values should be stored as they are by the binary codec
+#[test]
+fn kvs_bincode_log_size() -> Result<()> {
+    let log_size = |codec| -> Result<u64> {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let options = KvStoreOptions { codec, ..KvStoreOptions::default() };
+        let store = KvStore::open_with_options(temp_dir.path(), options)?;
+        store.set(b"key".to_vec(), vec![0xff; 1000])?;
+        drop(store);
+        Ok(fs::metadata(temp_dir.path().join("1.log"))?.len())
+    };
+    assert!(log_size(LogCodec::Bincode)? < 1100);
+    assert!(log_size(LogCodec::Json)? > 3000);
+    Ok(())
+}
+
+// Each engine should describe its directory in a manifest, and refuse a
+// directory written by the other one
+#[test]
+fn data_dir_manifest() -> Result<()> {
+    let kvs_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(KvStore::open(kvs_dir.path())?);
+    let manifest = Manifest::load(kvs_dir.path())?.expect("no manifest written");
+    assert_eq!(manifest.engine, "kvs");
+    assert_eq!(manifest.codec, Some(LogCodec::Json));
+    assert!(manifest.created_at > 0);
+
+    // reopening keeps the same store
+    drop(KvStore::open(kvs_dir.path())?);
+    assert_eq!(Manifest::load(kvs_dir.path())?, Some(manifest));
+
+    let sled_dir = TempDir::new().expect("unable to create temporary working directory");
+    drop(SledKvsEngine::open(sled_dir.path())?);
+    let manifest = Manifest::load(sled_dir.path())?.expect("no manifest written");
+    assert_eq!(manifest.engine, "sled");
+    assert_eq!(manifest.codec, None);
+
+    match SledKvsEngine::open(kvs_dir.path()) {
+        Err(KvsError::WrongEngine { expected, found }) => {
+            assert_eq!((expected.as_str(), found.as_str()), ("sled", "kvs"))
+        },
+        _ => panic!("expected a wrong engine"),
+    }
+    match KvStore::open(sled_dir.path()) {
+        Err(KvsError::WrongEngine { expected, found }) => {
+            assert_eq!((expected.as_str(), found.as_str()), ("kvs", "sled"))
+        },
+        _ => panic!("expected a wrong engine"),
+    }
+    // turned away without leaving a lock file behind
+    assert!(!sled_dir.path().join("LOCK").exists());
+    Ok(())
+}
+
+// Directories from before manifests should still be told apart, by their
+// `.engine_flag` or by their files
+#[test]
+fn data_dir_without_manifest() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key".to_vec(), b"value".to_vec())?;
+    drop(store);
+    fs::remove_file(temp_dir.path().join("MANIFEST"))?;
+    assert!(SledKvsEngine::open(temp_dir.path()).is_err());
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join(".engine_flag"), "sled")?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(SledKvsEngine::open(temp_dir.path())?);
+    assert!(!temp_dir.path().join(".engine_flag").exists());
+    assert!(temp_dir.path().join("MANIFEST").exists());
+
+    // other `.log` files do not make a directory a kvs store
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    fs::write(temp_dir.path().join("server.log"), "started")?;
+    drop(SledKvsEngine::open(temp_dir.path())?);
+    Ok(())
+}
+
+// Only one store at a time should be able to write to a directory, while
+// read-only ones can open it next to the writer
+#[test]
+fn kvs_directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Locked) => {},
+        _ => panic!("expected the directory to be locked"),
+    }
+    assert!(KvStore::upgrade(temp_dir.path(), LogCodec::Json).is_err());
+
+    let options = KvStoreOptions { read_only: true, ..KvStoreOptions::default() };
+    let reader = KvStore::open_with_options(temp_dir.path(), options)?;
+    assert_eq!(reader.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    match reader.set(b"key2".to_vec(), b"value2".to_vec()) {
+        Err(KvsError::ReadOnly) => {},
+        _ => panic!("expected the store to be read-only"),
+    }
+    assert!(reader.remove(b"key1".to_vec()).is_err());
+    assert!(reader.compact().is_err());
+
+    // the lock goes away with the last handle
+    let clone = store.clone();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(clone);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    Ok(())
+}
+
+// The lock should go away with the last handle even while the background
+// threads are holding on to the store
+#[test]
+fn kvs_lock_released_with_last_handle() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = || KvStoreOptions {
+        sync: SyncPolicy::Interval(Duration::from_millis(1)),
+        sweep_interval: Duration::from_millis(1),
+        ..KvStoreOptions::default()
+    };
+    for i in 0..200 {
+        let store = KvStore::open_with_options(temp_dir.path(), options())?;
+        store.set(format!("key{}", i).into_bytes(), b"value".to_vec())?;
+        // lets the sweeper and syncer come around while the store is dropped
+        thread::sleep(Duration::from_millis(1));
+        drop(store);
+    }
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan(.., None)?.len(), 200);
+    Ok(())
+}
+
+fn dir_content(dir: &TempDir) -> Result<Vec<(String, u64)>> {
+    let mut content = vec![];
+    for f in fs::read_dir(dir.path())? {
+        let f = f?;
+        content.push((f.file_name().to_string_lossy().into_owned(), f.metadata()?.len()));
+    }
+    content.sort();
+    Ok(content)
+}
+
+// A read-only store should keep reading keys after the writer next to it
+// compacts away the logs they were in
+#[test]
+fn kvs_read_only_survives_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i).into_bytes(), b"old".to_vec())?;
+    }
+    let reader = KvStore::open_read_only(temp_dir.path())?;
+
+    for i in 0..5 {
+        store.set(format!("key{}", i).into_bytes(), b"new".to_vec())?;
+    }
+    store.remove(b"key9".to_vec())?;
+    store.compact()?;
+    assert!(!temp_dir.path().join("1.log").exists());
+
+    for i in 0..5 {
+        assert_eq!(reader.get(format!("key{}", i).into_bytes())?, Some(b"new".to_vec()));
+    }
+    for i in 5..9 {
+        assert_eq!(reader.get(format!("key{}", i).into_bytes())?, Some(b"old".to_vec()));
+    }
+    assert_eq!(reader.get(b"key9".to_vec())?, None);
+    assert_eq!(reader.scan(.., None)?.len(), 9);
+    Ok(())
+}
+
+// A read-only store should replay the index without touching the directory
+#[test]
+fn kvs_open_read_only() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    store.compact()?;
+    store.set(b"key1".to_vec(), b"value3".to_vec())?;
+    drop(store);
+
+    // leave a torn write behind, a writer would cut it off
+    let log = temp_dir.path().join("3.log");
+    let len = fs::metadata(&log)?.len();
+    OpenOptions::new().write(true).open(&log)?.set_len(len - 1)?;
+    let content = dir_content(&temp_dir)?;
+
+    let store = KvStore::open_read_only(temp_dir.path())?;
+    assert_eq!(store.scan(.., None)?, vec![kv("key1", "value1"), kv("key2", "value2")]);
+    match store.set(b"key3".to_vec(), b"value3".to_vec()) {
+        Err(KvsError::ReadOnly) => {},
+        _ => panic!("expected the store to be read-only"),
+    }
+    match store.remove(b"key1".to_vec()) {
+        Err(KvsError::ReadOnly) => {},
+        _ => panic!("expected the store to be read-only"),
+    }
+    let mut batch = WriteBatch::new();
+    batch.set(b"key3".to_vec(), b"value3".to_vec());
+    assert!(store.write_batch(batch).is_err());
+    drop(store);
+
+    assert_eq!(dir_content(&temp_dir)?, content);
+    Ok(())
+}
+
+fn key_expiry<E: KvsEngine>(engine: E) -> Result<()> {
+    let ttl = Duration::from_millis(200);
+    engine.set_with_ttl(b"key1".to_vec(), b"value1".to_vec(), ttl)?;
+    engine.set_with_ttl(b"key2".to_vec(), b"value2".to_vec(), ttl)?;
+    engine.set(b"key3".to_vec(), b"value3".to_vec())?;
+    engine.expire(b"key3".to_vec(), ttl)?;
+    engine.set(b"key4".to_vec(), b"value4".to_vec())?;
+    // a plain set makes the key live forever again
+    engine.set(b"key2".to_vec(), b"value5".to_vec())?;
+    match engine.expire(b"key5".to_vec(), ttl) {
+        Err(KvsError::NoEntryError) => {},
+        _ => panic!("expected no entry for a missing key"),
+    }
+
+    assert_eq!(engine.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(engine.scan(.., None)?.len(), 4);
+    thread::sleep(Duration::from_millis(400));
+
+    assert_eq!(engine.get(b"key1".to_vec())?, None);
+    assert_eq!(engine.get(b"key3".to_vec())?, None);
+    assert_eq!(engine.scan(.., Some(1))?, vec![kv("key2", "value5")]);
+    assert_eq!(engine.scan(.., None)?, vec![kv("key2", "value5"), kv("key4", "value4")]);
+    match engine.expire(b"key1".to_vec(), ttl) {
+        Err(KvsError::NoEntryError) => {},
+        _ => panic!("expected no entry for an expired key"),
+    }
+    match engine.remove(b"key3".to_vec()) {
+        Err(KvsError::NoEntryError) => {},
+        _ => panic!("expected no entry for an expired key"),
+    }
+    Ok(())
+}
+
+#[test]
+fn kvs_key_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    key_expiry(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_key_expiry() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    key_expiry(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Expired sled keys should be dropped in the background, even if nobody
+// reads them again
+#[test]
+fn sled_expired_keys_are_swept() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let db = sled::open(temp_dir.path())?;
+    let engine = SledKvsEngine::new(db.clone())?;
+    engine.set_with_ttl(b"session".to_vec(), b"token".to_vec(), Duration::from_millis(100))?;
+    engine.set(b"key1".to_vec(), b"value1".to_vec())?;
+    assert!(db.contains_key(b"session")?);
+
+    thread::sleep(Duration::from_millis(2500));
+    assert!(!db.contains_key(b"session")?);
+    assert!(db.contains_key(b"key1")?);
+
+    // the sweeper does not keep the database open
+    drop(engine);
+    drop(db);
+    drop(SledKvsEngine::new(sled::open(temp_dir.path())?)?);
+    Ok(())
+}
+
+// Expiry times should survive a reopen, and compaction should leave
+// expired entries behind
+#[test]
+fn kvs_expiry_persists() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set_with_ttl(b"key1".to_vec(), b"value1".to_vec(), Duration::from_millis(300))?;
+    store.set_with_ttl(b"key2".to_vec(), b"value2".to_vec(), Duration::from_secs(3600))?;
+    store.set(b"key3".to_vec(), b"value3".to_vec())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    thread::sleep(Duration::from_millis(500));
+    assert_eq!(store.get(b"key1".to_vec())?, None);
+    store.compact()?;
+    drop(store);
+
+    let logs = fs::read_dir(temp_dir.path())?
+        .map(|f| f.map(|f| f.path()))
+        .collect::<std::io::Result<Vec<_>>>()?
+        .into_iter()
+        .filter(|p| p.extension() == Some("log".as_ref()))
+        .map(fs::read)
+        .collect::<std::io::Result<Vec<_>>>()?
+        .concat();
+    // keys are logged as JSON arrays of bytes
+    assert!(String::from_utf8_lossy(&logs).contains("[107,101,121,50]"));
+    assert!(!String::from_utf8_lossy(&logs).contains("[107,101,121,49]"));
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan(.., None)?, vec![kv("key2", "value2"), kv("key3", "value3")]);
+    Ok(())
+}
+
+fn compare_and_swap<E: KvsEngine>(engine: E) -> Result<()> {
+    engine.compare_and_swap(b"key1".to_vec(), None, Some(b"value1".to_vec()))?;
+    match engine.compare_and_swap(b"key1".to_vec(), None, Some(b"value2".to_vec())) {
+        Err(KvsError::Conflict) => {},
+        _ => panic!("expected a conflict on an existing key"),
+    }
+    match engine.compare_and_swap(b"key1".to_vec(), Some(b"value3".to_vec()), Some(b"value2".to_vec())) {
+        Err(KvsError::Conflict) => {},
+        _ => panic!("expected a conflict on another value"),
+    }
+    engine.compare_and_swap(b"key1".to_vec(), Some(b"value1".to_vec()), Some(b"value2".to_vec()))?;
+    assert_eq!(engine.get(b"key1".to_vec())?, Some(b"value2".to_vec()));
+
+    engine.compare_and_swap(b"key1".to_vec(), Some(b"value2".to_vec()), None)?;
+    assert_eq!(engine.get(b"key1".to_vec())?, None);
+    engine.compare_and_swap(b"key1".to_vec(), None, None)?;
+
+    assert!(engine.set_if_absent(b"key2".to_vec(), b"value1".to_vec())?);
+    assert!(!engine.set_if_absent(b"key2".to_vec(), b"value2".to_vec())?);
+    assert_eq!(engine.get(b"key2".to_vec())?, Some(b"value1".to_vec()));
+
+    // an expired key is as good as missing
+    engine.set_with_ttl(b"key3".to_vec(), b"value1".to_vec(), Duration::from_millis(100))?;
+    thread::sleep(Duration::from_millis(200));
+    assert!(engine.set_if_absent(b"key3".to_vec(), b"value2".to_vec())?);
+    assert_eq!(engine.get(b"key3".to_vec())?, Some(b"value2".to_vec()));
+
+    // concurrent increments must not lose updates
+    let handles = (0..4).map(|_| {
+        let engine = engine.clone();
+        thread::spawn(move || -> Result<()> {
+            let mut done = 0;
+            while done < 25 {
+                let current = engine.get(b"counter".to_vec())?;
+                let n = current.as_ref()
+                    .map(|v| String::from_utf8_lossy(v).parse::<u64>().unwrap())
+                    .unwrap_or(0);
+                match engine.compare_and_swap(b"counter".to_vec(), current, Some((n + 1).to_string().into_bytes())) {
+                    Ok(()) => done += 1,
+                    Err(KvsError::Conflict) => {},
+                    Err(e) => return Err(e),
+                }
+            }
+            Ok(())
+        })
+    }).collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(engine.get(b"counter".to_vec())?, Some(b"100".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn kvs_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compare_and_swap(KvStore::open(temp_dir.path())?)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, None);
+    assert_eq!(store.get(b"key2".to_vec())?, Some(b"value1".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn sled_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    compare_and_swap(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn versioned_writes<E: KvsEngine>(engine: E) -> Result<()> {
+    assert_eq!(engine.get_versioned(b"key1".to_vec())?, None);
+    let v1 = engine.compare_version_and_swap(b"key1".to_vec(), None, Some(b"value1".to_vec()))?.unwrap();
+    assert_eq!(engine.get_versioned(b"key1".to_vec())?, Some((b"value1".to_vec(), v1)));
+
+    // every write gets a higher version, whatever the key
+    engine.set(b"key2".to_vec(), b"value1".to_vec())?;
+    let (_, v2) = engine.get_versioned(b"key2".to_vec())?.unwrap();
+    assert!(v2 > v1);
+    engine.set(b"key1".to_vec(), b"value1".to_vec())?;
+    let (_, v3) = engine.get_versioned(b"key1".to_vec())?.unwrap();
+    assert!(v3 > v2);
+
+    match engine.compare_version_and_swap(b"key1".to_vec(), Some(v1), Some(b"value2".to_vec())) {
+        Err(KvsError::Conflict) => {},
+        _ => panic!("expected a conflict on a stale version"),
+    }
+    match engine.compare_version_and_swap(b"key1".to_vec(), None, Some(b"value2".to_vec())) {
+        Err(KvsError::Conflict) => {},
+        _ => panic!("expected a conflict on an existing key"),
+    }
+    let v4 = engine.compare_version_and_swap(b"key1".to_vec(), Some(v3), Some(b"value2".to_vec()))?.unwrap();
+    assert!(v4 > v3);
+    assert_eq!(engine.get(b"key1".to_vec())?, Some(b"value2".to_vec()));
+
+    assert_eq!(engine.compare_version_and_swap(b"key1".to_vec(), Some(v4), None)?, None);
+    assert_eq!(engine.get_versioned(b"key1".to_vec())?, None);
+
+    let mut batch = WriteBatch::new();
+    batch.set(b"key3".to_vec(), b"value1".to_vec());
+    batch.set(b"key4".to_vec(), b"value1".to_vec());
+    engine.write_batch(batch)?;
+    let (_, v5) = engine.get_versioned(b"key3".to_vec())?.unwrap();
+    let (_, v6) = engine.get_versioned(b"key4".to_vec())?.unwrap();
+    assert!(v4 < v5 && v5 < v6);
+    Ok(())
+}
+
+#[test]
+fn kvs_versioned_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    versioned_writes(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_versioned_writes() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    versioned_writes(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn counters<E: KvsEngine>(engine: E) -> Result<()> {
+    assert_eq!(engine.incr(b"counter".to_vec(), 1)?, 1);
+    assert_eq!(engine.incr(b"counter".to_vec(), 41)?, 42);
+    assert_eq!(engine.decr(b"counter".to_vec(), 50)?, -8);
+    assert_eq!(engine.get(b"counter".to_vec())?, Some(b"-8".to_vec()));
+
+    engine.set(b"key1".to_vec(), b"value1".to_vec())?;
+    match engine.incr(b"key1".to_vec(), 1) {
+        Err(KvsError::NotANumber) => {},
+        _ => panic!("expected a value that is not a number"),
+    }
+    assert_eq!(engine.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    engine.set(b"key2".to_vec(), i64::MAX.to_string().into_bytes())?;
+    match engine.incr(b"key2".to_vec(), 1) {
+        Err(KvsError::Overflow) => {},
+        _ => panic!("expected an overflow"),
+    }
+
+    // counting keeps the expiry time
+    engine.set_with_ttl(b"key3".to_vec(), b"10".to_vec(), Duration::from_millis(200))?;
+    assert_eq!(engine.incr(b"key3".to_vec(), 5)?, 15);
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get(b"key3".to_vec())?, None);
+    assert_eq!(engine.incr(b"key3".to_vec(), 5)?, 5);
+
+    let handles = (0..4).map(|_| {
+        let engine = engine.clone();
+        thread::spawn(move || -> Result<()> {
+            for _ in 0..25 {
+                engine.incr(b"hits".to_vec(), 2)?;
+                engine.decr(b"hits".to_vec(), 1)?;
+            }
+            Ok(())
+        })
+    }).collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(engine.get(b"hits".to_vec())?, Some(b"100".to_vec()));
+    Ok(())
+}
+
+#[test]
+fn kvs_counters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    counters(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_counters() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    counters(SledKvsEngine::open(temp_dir.path())?)
+}
+
+fn append_and_ranges<E: KvsEngine>(engine: E) -> Result<()> {
+    assert_eq!(engine.get_range(b"key1".to_vec(), 0, 10)?, None);
+    assert_eq!(engine.append(b"key1".to_vec(), b"hello".to_vec())?, 5);
+    assert_eq!(engine.append(b"key1".to_vec(), b", ".to_vec())?, 7);
+    assert_eq!(engine.append(b"key1".to_vec(), b"world".to_vec())?, 12);
+    assert_eq!(engine.get(b"key1".to_vec())?, Some(b"hello, world".to_vec()));
+
+    assert_eq!(engine.get_range(b"key1".to_vec(), 0, 5)?, Some(b"hello".to_vec()));
+    assert_eq!(engine.get_range(b"key1".to_vec(), 3, 6)?, Some(b"lo, wo".to_vec()));
+    assert_eq!(engine.get_range(b"key1".to_vec(), 7, 100)?, Some(b"world".to_vec()));
+    assert_eq!(engine.get_range(b"key1".to_vec(), 12, 1)?, Some(vec![]));
+    assert_eq!(engine.get_range(b"key1".to_vec(), 100, u64::MAX)?, Some(vec![]));
+
+    // a plain set starts over
+    engine.set(b"key1".to_vec(), b"abc".to_vec())?;
+    assert_eq!(engine.append(b"key1".to_vec(), b"def".to_vec())?, 6);
+    assert_eq!(engine.get_range(b"key1".to_vec(), 2, 2)?, Some(b"cd".to_vec()));
+
+    // appending keeps the expiry time
+    engine.set_with_ttl(b"key2".to_vec(), b"abc".to_vec(), Duration::from_millis(200))?;
+    assert_eq!(engine.append(b"key2".to_vec(), b"def".to_vec())?, 6);
+    thread::sleep(Duration::from_millis(300));
+    assert_eq!(engine.get_range(b"key2".to_vec(), 0, 3)?, None);
+    assert_eq!(engine.append(b"key2".to_vec(), b"ghi".to_vec())?, 3);
+
+    let handles = (0..4u8).map(|i| {
+        let engine = engine.clone();
+        thread::spawn(move || -> Result<()> {
+            for _ in 0..25 {
+                engine.append(b"list".to_vec(), vec![b'a' + i])?;
+            }
+            Ok(())
+        })
+    }).collect::<Vec<_>>();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    let mut list = engine.get(b"list".to_vec())?.unwrap();
+    list.sort_unstable();
+    assert_eq!(list, [[b'a'; 25], [b'b'; 25], [b'c'; 25], [b'd'; 25]].concat());
+    Ok(())
+}
+
+#[test]
+fn kvs_append_and_ranges() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    append_and_ranges(KvStore::open(temp_dir.path())?)
+}
+
+#[test]
+fn sled_append_and_ranges() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    append_and_ranges(SledKvsEngine::open(temp_dir.path())?)
+}
+
+// Appending to a large value should not write it out again every time
+#[test]
+fn kvs_append_log_size() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let value = vec![b'x'; 10_000];
+    store.set(b"key1".to_vec(), value.clone())?;
+    let size = fs::metadata(temp_dir.path().join("1.log"))?.len();
+
+    let mut expected = value;
+    for i in 0..100u32 {
+        let suffix = i.to_string().into_bytes();
+        expected.extend_from_slice(&suffix);
+        store.append(b"key1".to_vec(), suffix)?;
+    }
+    assert_eq!(store.get(b"key1".to_vec())?, Some(expected.clone()));
+    assert!(fs::metadata(temp_dir.path().join("1.log"))?.len() < size * 10);
+    let tail = expected[expected.len() - 20..].to_vec();
+    assert_eq!(store.get_range(b"key1".to_vec(), expected.len() as u64 - 20, 20)?, Some(tail));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(expected.clone()));
+    store.append(b"key1".to_vec(), b"!".to_vec())?;
+    expected.push(b'!');
+    store.compact()?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(expected.clone()));
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(expected.clone()));
+    assert_eq!(store.append(b"key1".to_vec(), b"?".to_vec())?, expected.len() as u64 + 1);
+    Ok(())
+}
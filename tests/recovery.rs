@@ -0,0 +1,331 @@
+use std::fs;
+
+use kvs::engine::{KvPair, KvStoreOptions, LogCodec, WriteBatch};
+use kvs::{KvStore, KvsEngine, KvsError, Result};
+use tempfile::TempDir;
+
+// Writes a few sets, removes and batches, returning the final log and the
+// content of the store after each write.
+fn write_history(dir: &TempDir) -> Result<(Vec<u8>, Vec<Vec<KvPair>>)> {
+    let store = KvStore::open(dir.path())?;
+    let mut states = vec![store.scan(.., None)?];
+
+    for i in 0..4 {
+        store.set(format!("key{}", i).into_bytes(), format!("value{}", i).into_bytes())?;
+        states.push(store.scan(.., None)?);
+    }
+    store.remove(b"key1".to_vec())?;
+    states.push(store.scan(.., None)?);
+
+    let mut batch = WriteBatch::new();
+    batch
+        .set(b"key0".to_vec(), b"batched".to_vec())
+        .remove(b"key2".to_vec())
+        .set(b"key9".to_vec(), b"value9".to_vec());
+    store.write_batch(batch)?;
+    states.push(store.scan(.., None)?);
+
+    store.set(b"key3".to_vec(), b"last".to_vec())?;
+    states.push(store.scan(.., None)?);
+    drop(store);
+
+    Ok((fs::read(dir.path().join("1.log"))?, states))
+}
+
+// A log cut at any byte offset should reopen with a prefix of the writes,
+// and keep accepting new writes afterwards.
+#[test]
+fn reopen_after_torn_write() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let (log, states) = write_history(&temp_dir)?;
+
+    let mut last_state = 0;
+    for len in 0..=log.len() {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        fs::write(temp_dir.path().join("1.log"), &log[..len])?;
+
+        let store = KvStore::open(temp_dir.path())?;
+        let content = store.scan(.., None)?;
+        let state = states
+            .iter()
+            .position(|s| *s == content)
+            .unwrap_or_else(|| panic!("log cut at {} reopened to an unknown state", len));
+        assert!(state >= last_state, "log cut at {} lost earlier writes", len);
+        last_state = state;
+
+        store.set(b"after".to_vec(), b"crash".to_vec())?;
+        drop(store);
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get(b"after".to_vec())?, Some(b"crash".to_vec()));
+    }
+    assert_eq!(last_state, states.len() - 1);
+
+    Ok(())
+}
+
+// A damaged record should be reported as corruption, both when it is read
+// and when the log is replayed
+#[test]
+fn detect_corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    store.set(b"key3".to_vec(), b"value3".to_vec())?;
+
+    // flip a bit in the middle of the second record
+    let path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&path)?;
+    let middle = log.len() / 2;
+    log[middle] ^= 0x01;
+    fs::write(&path, &log)?;
+
+    match store.get(b"key2".to_vec()) {
+        Err(KvsError::Corruption { log_no: 1, .. }) => {},
+        other => panic!("expected corruption, got {:?}", other),
+    }
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    drop(store);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { log_no: 1, offset }) => assert!(offset > 0),
+        Err(e) => panic!("expected corruption, got {:?}", e),
+        Ok(_) => panic!("expected corruption"),
+    }
+    Ok(())
+}
+
+// A damaged length should be reported as corruption on open, rather than
+// taken for a torn write and cut off along with every record after it
+#[test]
+fn detect_corrupted_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    store.set(b"key3".to_vec(), b"value3".to_vec())?;
+    drop(store);
+
+    // the length of each frame leads its header, little-endian
+    let path = temp_dir.path().join("1.log");
+    let mut log = fs::read(&path)?;
+    let first_len = u32::from_le_bytes([log[0], log[1], log[2], log[3]]) as usize;
+    let second = 8 + first_len;
+    log[second + 3] ^= 0x80;
+    fs::write(&path, &log)?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { log_no: 1, offset }) => assert_eq!(offset, second as u64),
+        Err(e) => panic!("expected corruption, got {:?}", e),
+        Ok(_) => panic!("expected corruption"),
+    }
+    assert_eq!(fs::read(&path)?, log);
+    Ok(())
+}
+
+// A torn write of a value holding whole frames of its own, like a copy of
+// a log, should still be taken for a torn tail
+#[test]
+fn torn_value_holding_frames() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..3 {
+        store.set(format!("key{}", i).into_bytes(), format!("value{}", i).into_bytes())?;
+    }
+    drop(store);
+    let blob = fs::read(temp_dir.path().join("1.log"))?;
+
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let options = KvStoreOptions { codec: LogCodec::Bincode, ..KvStoreOptions::default() };
+    let store = KvStore::open_with_options(temp_dir.path(), options)?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    store.set(b"blob".to_vec(), blob)?;
+    drop(store);
+
+    // cut the log inside the last frame of the blob, leaving the others whole
+    let path = temp_dir.path().join("1.log");
+    let len = fs::metadata(&path)?.len();
+    fs::OpenOptions::new().write(true).open(&path)?.set_len(len - 20)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"value1".to_vec()));
+    assert_eq!(store.get(b"blob".to_vec())?, None);
+    Ok(())
+}
+
+// Opening a compacted store should index the compacted log from its hint
+// file, and fall back to replaying the log if the hint file is damaged
+#[test]
+fn reopen_from_hint_file() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for i in 0..10 {
+        store.set(format!("key{}", i).into_bytes(), format!("value{}", i).into_bytes())?;
+    }
+    store.remove(b"key0".to_vec())?;
+    store.compact()?;
+    store.set(b"key1".to_vec(), b"after".to_vec())?;
+    drop(store);
+
+    // sealed logs are compacted into 2.log, new writes go to 3.log
+    let log = temp_dir.path().join("2.log");
+    let hint = temp_dir.path().join("2.hint");
+    assert!(hint.exists());
+    assert!(!temp_dir.path().join("1.hint").exists());
+
+    // damage a value: replaying the log would fail, the hints don't look at it
+    let mut content = fs::read(&log)?;
+    // values are serialized as arrays of bytes
+    let value5 = b"[118,97,108,117,101,53]";
+    let at = content.windows(value5.len()).position(|w| w == value5).unwrap() + 1;
+    content[at] ^= 0x01;
+    fs::write(&log, &content)?;
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get(b"key0".to_vec())?, None);
+    assert_eq!(store.get(b"key1".to_vec())?, Some(b"after".to_vec()));
+    assert_eq!(store.get(b"key9".to_vec())?, Some(b"value9".to_vec()));
+    assert!(store.get(b"key5".to_vec()).is_err());
+    drop(store);
+
+    // a cut hint file is ignored and the log replayed
+    let len = fs::metadata(&hint)?.len();
+    fs::OpenOptions::new().write(true).open(&hint)?.set_len(len - 1)?;
+    assert!(KvStore::open(temp_dir.path()).is_err());
+
+    content[at] ^= 0x01;
+    fs::write(&log, &content)?;
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.scan(.., None)?.len(), 9);
+    assert_eq!(store.get(b"key5".to_vec())?, Some(b"value5".to_vec()));
+    Ok(())
+}
+
+// Writes a log in the original format: JSON entries with string keys and
+// values, one after the other, and no header
+fn write_legacy_store(dir: &TempDir) -> Result<()> {
+    let log = concat!(
+        r#"{"Set":["key1","value1"]}{"Set":["key2","value2"]}"#,
+        r#"{"Remove":"key1"}{"Set":["key3","value3"]}{"Set":["key2","value4"]}"#,
+        r#"{"Set":["key4","#,
+    );
+    fs::write(dir.path().join("1.log"), log)?;
+    Ok(())
+}
+
+// A store in the original format should be refused until it is upgraded,
+// and keep its content once it is
+#[test]
+fn upgrade_legacy_store() -> Result<()> {
+    let expected = vec![
+        (b"key2".to_vec(), b"value4".to_vec()),
+        (b"key3".to_vec(), b"value3".to_vec()),
+    ];
+    for codec in &[LogCodec::Json, LogCodec::Bincode] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        write_legacy_store(&temp_dir)?;
+        match KvStore::open(temp_dir.path()) {
+            Err(KvsError::OutdatedFormat(0)) => {},
+            Err(e) => panic!("expected an outdated format, got {:?}", e),
+            Ok(_) => panic!("expected an outdated format"),
+        }
+
+        KvStore::upgrade(temp_dir.path(), *codec)?;
+        // upgrading twice is harmless
+        KvStore::upgrade(temp_dir.path(), *codec)?;
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.scan(.., None)?, expected);
+        store.set(b"key5".to_vec(), b"value5".to_vec())?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.scan(.., None)?.len(), 3);
+        assert_eq!(store.get(b"key5".to_vec())?, Some(b"value5".to_vec()));
+    }
+    Ok(())
+}
+
+// An upgrade interrupted after rewriting the logs but before saving the
+// manifest should keep the codec the logs were rewritten with, whether it
+// is resumed or the store is opened right away
+#[test]
+fn resume_interrupted_upgrade() -> Result<()> {
+    for codec in &[LogCodec::Json, LogCodec::Bincode] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        write_legacy_store(&temp_dir)?;
+        KvStore::upgrade(temp_dir.path(), *codec)?;
+        let manifest = temp_dir.path().join("MANIFEST");
+        fs::remove_file(&manifest)?;
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.scan(.., None)?.len(), 2);
+        drop(store);
+        assert!(fs::read_to_string(&manifest)?.contains(&format!("{:?}", codec)));
+
+        fs::remove_file(&manifest)?;
+        KvStore::upgrade(temp_dir.path(), LogCodec::Json)?;
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get(b"key2".to_vec())?, Some(b"value4".to_vec()));
+        store.set(b"key5".to_vec(), b"value5".to_vec())?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.scan(.., None)?.len(), 3);
+    }
+    Ok(())
+}
+
+// A store written by a newer version should be refused rather than misread
+#[test]
+fn refuse_unknown_format_version() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    drop(store);
+
+    let path = temp_dir.path().join("MANIFEST");
+    let manifest = fs::read_to_string(&path)?;
+    assert!(manifest.contains(r#""format_version": 4"#));
+    fs::write(&path, manifest.replace(r#""format_version": 4"#, r#""format_version": 99"#))?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::UnsupportedFormat(99)) => {},
+        Err(e) => panic!("expected an unsupported format, got {:?}", e),
+        Ok(_) => panic!("expected an unsupported format"),
+    }
+    assert!(KvStore::upgrade(temp_dir.path(), LogCodec::Json).is_err());
+    Ok(())
+}
+
+// Versions must keep growing across reopens, even once compaction has
+// dropped the tombstones holding the highest ones
+#[test]
+fn versions_survive_reopen_and_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set(b"key1".to_vec(), b"value1".to_vec())?;
+    let (_, v1) = store.get_versioned(b"key1".to_vec())?.unwrap();
+    for i in 0..10 {
+        store.set(format!("key{}", i + 2).into_bytes(), b"value".to_vec())?;
+        store.remove(format!("key{}", i + 2).into_bytes())?;
+    }
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_versioned(b"key1".to_vec())?, Some((b"value1".to_vec(), v1)));
+    store.set(b"key2".to_vec(), b"value2".to_vec())?;
+    let (_, v2) = store.get_versioned(b"key2".to_vec())?.unwrap();
+    assert!(v2 > v1 + 20);
+    store.remove(b"key2".to_vec())?;
+    store.compact()?;
+    drop(store);
+
+    // key1 is all the compacted log holds, and key2 left only a tombstone
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get_versioned(b"key1".to_vec())?, Some((b"value1".to_vec(), v1)));
+    store.set(b"key3".to_vec(), b"value3".to_vec())?;
+    let (_, v3) = store.get_versioned(b"key3".to_vec())?.unwrap();
+    assert!(v3 > v2 + 1);
+    Ok(())
+}